@@ -0,0 +1,297 @@
+use crate::rules::{Attribute, Color, Style};
+
+/// How escape sequences already in the input are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnsiMode {
+    /// Remove escape sequences before matching, so only the rules' styles
+    /// remain.
+    Strip,
+    /// Match the visible text only and keep the input's styles beneath
+    /// the rules' styles. Other escape sequences, such as hyperlinks, are
+    /// put back where they were in ANSI output.
+    Preserve,
+}
+
+/// A piece of text and the style escape sequences gave it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StyledText {
+    pub text: String,
+    pub style: Style,
+}
+
+/// A line with its escape sequences removed, and the styles they gave
+/// its text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StyledLine {
+    /// The visible text, including the line ending.
+    pub text: String,
+    /// The styled byte ranges of `text`, in order and without overlaps.
+    /// Unstyled text and the line ending are not covered.
+    pub spans: Vec<StyledSpan>,
+    /// Escape sequences other than SGR, with the byte offset in `text`
+    /// they were found at.
+    pub escapes: Vec<(usize, String)>,
+}
+
+/// A byte range of a [`StyledLine`] in one style.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StyledSpan {
+    pub start: usize,
+    pub end: usize,
+    pub style: Style,
+}
+
+/// Interprets ANSI escape sequences the way a terminal does.
+///
+/// SGR sequences (`\x1b[...m`) change the current style, which carries
+/// over from one line to the next until it is reset. Other escape
+/// sequences, such as cursor movements and window titles, are dropped.
+///
+/// # Examples
+///
+/// ```rust
+/// use highlite::ansi::AnsiParser;
+/// use highlite::rules::Attribute;
+///
+/// let pieces = AnsiParser::default().parse("ok \x1b[1;31merror\x1b[0m\n");
+/// assert_eq!(pieces.len(), 3);
+/// assert_eq!(pieces[1].text, "error");
+/// assert_eq!(pieces[1].style.attributes, vec![Attribute::Bold]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AnsiParser {
+    style: Style,
+}
+
+impl AnsiParser {
+    /// Splits `input` into pieces of text with the style they are
+    /// displayed in. Adjacent pieces never have the same style.
+    pub fn parse(&mut self, input: &str) -> Vec<StyledText> {
+        let mut pieces: Vec<StyledText> = Vec::new();
+        self.scan(input, |text, style| push(&mut pieces, text, style), |_| {});
+        pieces
+    }
+
+    /// Removes the escape sequences of a line and records the styles of
+    /// its text.
+    ///
+    /// Returns `None` if the line has no escape sequences and no style is
+    /// active, so that it can be used as is.
+    pub fn parse_line(&mut self, input: &str) -> Option<StyledLine> {
+        if !input.contains('\x1b') && self.style.is_empty() {
+            return None;
+        }
+
+        let mut pieces: Vec<StyledText> = Vec::new();
+        let mut escapes = Vec::new();
+        // 两个回调都需要访问已输出文本的长度
+        let len = std::cell::Cell::new(0);
+        self.scan(
+            input,
+            |text, style| {
+                len.set(len.get() + text.len());
+                push(&mut pieces, text, style);
+            },
+            |escape| escapes.push((len.get(), escape.to_string())),
+        );
+
+        let mut line = StyledLine { escapes, ..Default::default() };
+        for piece in pieces {
+            let start = line.text.len();
+            line.text.push_str(&piece.text);
+            if !piece.style.is_empty() {
+                line.spans.push(StyledSpan { start, end: line.text.len(), style: piece.style });
+            }
+        }
+
+        // 与规则的区间一致，样式不包含行尾的换行符
+        let line_end = line.text.trim_end_matches(['\n', '\r']).len();
+        line.spans.retain_mut(|span| {
+            span.end = span.end.min(line_end);
+            span.start < span.end
+        });
+        Some(line)
+    }
+
+    /// Reports the text of `input` with the style it is displayed in, and
+    /// the escape sequences other than SGR, in order.
+    fn scan(&mut self, input: &str, mut text: impl FnMut(&str, &Style), mut escape: impl FnMut(&str)) {
+        let mut rest = input;
+        while let Some(esc) = rest.find('\x1b') {
+            if esc > 0 {
+                text(&rest[..esc], &self.style);
+            }
+            let (sequence, len) = escape_sequence(&rest[esc..]);
+            match sequence {
+                Some(params) => self.apply_sgr(params),
+                None => escape(&rest[esc..esc + len]),
+            }
+            rest = &rest[esc + len..];
+        }
+        if !rest.is_empty() {
+            text(rest, &self.style);
+        }
+    }
+
+    /// Applies the parameters of an SGR sequence to the current style.
+    /// Unknown parameters are ignored.
+    fn apply_sgr(&mut self, params: &str) {
+        let number = |param: &str| param.parse::<u16>().unwrap_or(0);
+        let mut params = params.split(';');
+        let style = &mut self.style;
+
+        // 空序列 \x1b[m 等同于重置
+        let mut next = Some(params.next().unwrap_or(""));
+        while let Some(param) = next {
+            // 冒号分隔的子参数（如 38:2::r:g:b、4:3）属于同一个参数
+            let mut sub = param.split(':');
+            let code = number(sub.next().unwrap_or(""));
+            let has_sub = param.contains(':');
+            match code {
+                0 => *style = Style::default(),
+                1 => add(style, Attribute::Bold),
+                2 => add(style, Attribute::Dim),
+                3 => add(style, Attribute::Italic),
+                // 4:0 关闭下划线，4:1 到 4:5 是各种样式的下划线
+                4 if has_sub && sub.next().map(number) == Some(0) => {
+                    style.attributes.retain(|a| *a != Attribute::Underline)
+                }
+                4 | 21 => add(style, Attribute::Underline),
+                7 => add(style, Attribute::Reverse),
+                9 => add(style, Attribute::Strikethrough),
+                22 => style.attributes.retain(|a| !matches!(a, Attribute::Bold | Attribute::Dim)),
+                23 => style.attributes.retain(|a| *a != Attribute::Italic),
+                24 => style.attributes.retain(|a| *a != Attribute::Underline),
+                27 => style.attributes.retain(|a| *a != Attribute::Reverse),
+                29 => style.attributes.retain(|a| *a != Attribute::Strikethrough),
+                30..=37 => style.foreground = Some(Color::Indexed { index: (code - 30) as u8 }),
+                38 if has_sub => style.foreground = colon_color(sub),
+                38 => style.foreground = extended_color(&mut params.by_ref().map(number)),
+                39 => style.foreground = None,
+                40..=47 => style.background = Some(Color::Indexed { index: (code - 40) as u8 }),
+                48 if has_sub => style.background = colon_color(sub),
+                48 => style.background = extended_color(&mut params.by_ref().map(number)),
+                49 => style.background = None,
+                90..=97 => style.foreground = Some(Color::Indexed { index: (code - 90 + 8) as u8 }),
+                100..=107 => style.background = Some(Color::Indexed { index: (code - 100 + 8) as u8 }),
+                _ => {}
+            }
+            next = params.next();
+        }
+    }
+}
+
+/// Appends `text` in `style`, merging it into the last piece if that has
+/// the same style.
+fn push(pieces: &mut Vec<StyledText>, text: &str, style: &Style) {
+    match pieces.last_mut() {
+        Some(last) if last.style == *style => last.text.push_str(text),
+        _ => pieces.push(StyledText { text: text.to_string(), style: style.clone() }),
+    }
+}
+
+/// Puts the `escapes` of a [`StyledLine`] back into `output`, the line
+/// rendered as ANSI escape sequences.
+///
+/// Offsets count the visible text only, skipping the SGR sequences of the
+/// rendering. Escapes go before the SGR sequences at their offset.
+pub(crate) fn insert_escapes(output: &mut String, escapes: &[(usize, String)]) {
+    if escapes.is_empty() {
+        return;
+    }
+    let rendered = std::mem::take(output);
+    let mut escapes = escapes.iter().peekable();
+    let mut visible = 0;
+    let mut rest = rendered.as_str();
+
+    loop {
+        while let Some((_, escape)) = escapes.next_if(|(offset, _)| *offset <= visible) {
+            output.push_str(escape);
+        }
+        let Some(c) = rest.chars().next() else {
+            break;
+        };
+        let len = if c == '\x1b' {
+            escape_sequence(rest).1
+        } else {
+            visible += c.len_utf8();
+            c.len_utf8()
+        };
+        output.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    // 偏移超出文本的序列放在最后
+    for (_, escape) in escapes {
+        output.push_str(escape);
+    }
+}
+
+/// Adds an attribute to a style unless it is already set.
+fn add(style: &mut Style, attr: Attribute) {
+    if !style.attributes.contains(&attr) {
+        style.attributes.push(attr);
+    }
+}
+
+/// Reads the color following `38` or `48`: `5;n` for a palette index or
+/// `2;r;g;b` for an RGB value.
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    let mut channel = || params.next().map(|value| value.min(255) as u8);
+    match channel()? {
+        5 => Some(Color::Indexed { index: channel()? }),
+        2 => Some(Color::RGB { r: channel()?, g: channel()?, b: channel()? }),
+        _ => None,
+    }
+}
+
+/// Reads the sub-parameters of `38:…` or `48:…`: `5:n` for a palette
+/// index or `2:[color space]:r:g:b` for an RGB value, where the color
+/// space id may be left out entirely.
+fn colon_color<'a>(sub: impl Iterator<Item = &'a str>) -> Option<Color> {
+    let channel = |param: &str| param.parse::<u16>().ok().map(|value| value.min(255) as u8);
+    let sub: Vec<&str> = sub.collect();
+    match sub.as_slice() {
+        ["5", index, ..] => Some(Color::Indexed { index: channel(index)? }),
+        ["2", _, r, g, b, ..] | ["2", r, g, b] => Some(Color::RGB { r: channel(r)?, g: channel(g)?, b: channel(b)? }),
+        _ => None,
+    }
+}
+
+/// Reads the escape sequence at the start of `input`, which begins with
+/// `\x1b`. Returns the parameters if it is an SGR sequence, and the
+/// length of the sequence in bytes.
+fn escape_sequence(input: &str) -> (Option<&str>, usize) {
+    let bytes = input.as_bytes();
+    match bytes.get(1) {
+        // CSI：参数字节、中间字节，然后是一个结束字节
+        Some(b'[') => {
+            let params_end = 2 + bytes[2..].iter().take_while(|b| (0x30..=0x3f).contains(*b)).count();
+            let final_idx = params_end + bytes[params_end..].iter().take_while(|b| (0x20..=0x2f).contains(*b)).count();
+            match bytes.get(final_idx) {
+                Some(b'm') if final_idx == params_end => (Some(&input[2..params_end]), final_idx + 1),
+                Some(0x40..=0x7e) => (None, final_idx + 1),
+                // 不完整的序列，丢弃到此为止
+                _ => (None, final_idx),
+            }
+        }
+        // OSC：以 BEL 或 ESC \ 结束
+        Some(b']') => {
+            let end = input[1..].find(['\x07', '\x1b']).map(|i| i + 1);
+            match end {
+                Some(i) if bytes[i] == 0x07 => (None, i + 1),
+                Some(i) if bytes.get(i + 1) == Some(&b'\\') => (None, i + 2),
+                Some(i) => (None, i),
+                None => (None, input.len()),
+            }
+        }
+        // 其他序列：中间字节，然后是一个结束字节，如 tput sgr0 输出的 ESC ( B
+        Some(0x20..=0x7e) => {
+            let final_idx = 1 + bytes[1..].iter().take_while(|b| (0x20..=0x2f).contains(*b)).count();
+            match bytes.get(final_idx) {
+                Some(0x30..=0x7e) => (None, final_idx + 1),
+                _ => (None, final_idx),
+            }
+        }
+        _ => (None, 1),
+    }
+}
@@ -1,7 +1,13 @@
-use crate::rules::Rule;
-use clap::Parser;
+use crate::ansi::AnsiMode;
+use crate::detect::DetectConfig;
+use crate::format::{HtmlOptions, OutputFormat, SvgOptions};
+use crate::rules::{Color, Context, Rule, RuleSet, Style};
+use crate::term::ColorDepth;
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -32,6 +38,9 @@ pub struct CliArgs {
     #[arg(short, long, help = "Path to the YAML config file (optional)")]
     pub config: Option<PathBuf>,
 
+    #[arg(long, help = "Don't look for a default config file")]
+    pub no_config: bool,
+
     /// Follow system journal logs in real-time (`journalctl -f`).
     #[arg(long, help = "Follow system journal logs (journalctl -f)")]
     pub follow_journal: bool,
@@ -42,28 +51,436 @@ pub struct CliArgs {
 
     /// Use a built-in preset (like `logs`, `json`, or `cpp`).
     /// Ignored if `--config` is provided.
-    #[arg(short, long, help = "Use built-in preset (optional)")]
+    #[arg(short, long, help = "Use a built-in or user preset (optional)")]
     pub preset: Option<String>,
 
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        help = "Directory to look for user presets in (repeatable)"
+    )]
+    pub preset_path: Vec<PathBuf>,
+
+    /// Color depth of the output terminal.
+    /// `auto` detects it from `COLORTERM` and `TERM`.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ColorDepthArg::Auto,
+        help = "Color depth of the terminal (auto, truecolor, 256, 16)"
+    )]
+    pub color_depth: ColorDepthArg,
+
+    /// When to emit colors.
+    /// `auto` honors `NO_COLOR` and `CLICOLOR_FORCE`, and otherwise only
+    /// colors output written to a terminal.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ColorChoice::Auto,
+        help = "When to use colors (auto, always, never)"
+    )]
+    pub color: ColorChoice,
+
+    /// Theme mapping rule kinds to styles: a built-in theme name
+    /// (`dark`, `light`) or the path to a theme file.
+    #[arg(
+        long,
+        default_value = crate::theme::DEFAULT_THEME,
+        help = "Built-in theme (dark, light) or path to a theme file"
+    )]
+    pub theme: String,
+
+    #[arg(
+        long,
+        value_name = "TAG",
+        help = "Only use rules with this tag (repeatable)"
+    )]
+    pub enable_tag: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "TAG",
+        help = "Don't use rules with this tag (repeatable)"
+    )]
+    pub disable_tag: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "ID",
+        help = "Don't use the rule with this id (repeatable)"
+    )]
+    pub disable_rule: Vec<String>,
+
+    #[arg(long, help = "Only print lines matched by at least one rule")]
+    pub only_matching_lines: bool,
+
+    #[arg(
+        long,
+        value_name = "SELECTOR",
+        help = "Only print lines matched by rules with this id, tag or kind (repeatable)"
+    )]
+    pub filter: Vec<String>,
+
+    #[arg(long, help = "Print the lines that are not matched instead")]
+    pub invert: bool,
+
+    #[arg(short = 'A', long, value_name = "NUM", help = "Print NUM lines after each printed match")]
+    pub after_context: Option<usize>,
+
+    #[arg(short = 'B', long, value_name = "NUM", help = "Print NUM lines before each printed match")]
+    pub before_context: Option<usize>,
+
+    #[arg(short = 'C', long, value_name = "NUM", help = "Print NUM lines before and after each printed match")]
+    pub context: Option<usize>,
+
+    /// The output format. Only `ansi` honors `--color` and
+    /// `--color-depth`.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormatArg::Ansi,
+        help = "Output format (ansi, html, json, jsonl, svg)"
+    )]
+    pub output: OutputFormatArg,
+
+    #[arg(long, help = "Use CSS classes and a stylesheet instead of inline styles in HTML output")]
+    pub html_classes: bool,
+
+    #[arg(long, help = "Produce a complete HTML document instead of a fragment")]
+    pub html_standalone: bool,
+
+    #[arg(long, value_name = "FAMILY", help = "CSS font family of SVG output")]
+    pub svg_font: Option<String>,
+
+    #[arg(long, value_name = "PX", value_parser = clap::value_parser!(u32).range(1..), help = "Font size of SVG output in pixels [default: 14]")]
+    pub svg_font_size: Option<u32>,
+
+    /// Accepts the same color notations as configuration files.
+    #[arg(long, value_name = "COLOR", help = "Background color of SVG output [default: the theme's]")]
+    pub svg_background: Option<Color>,
+
+    #[arg(long, value_name = "COLUMNS", help = "Wrap SVG output after COLUMNS columns [default: the longest line]")]
+    pub svg_width: Option<usize>,
+
+    /// Without this option, escape sequences in the input are passed
+    /// through in ANSI output and preserved in other formats.
+    #[arg(long, value_enum, value_name = "MODE", help = "Strip or preserve escape sequences in the input (strip, preserve)")]
+    pub ansi: Option<AnsiModeArg>,
+
+    /// A subcommand to run instead of highlighting input.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Subcommands of `highlite`.
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// List the built-in and user presets with their descriptions.
+    ListPresets,
+
+    /// Print the rules of a preset as a YAML configuration file.
+    ///
+    /// The output can be saved and edited as a starting point for a
+    /// custom configuration.
+    DumpPreset {
+        /// Name of a built-in or user preset.
+        name: String,
+    },
+}
+
+/// Values accepted by `--color`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// Color only when writing to a terminal, honoring `NO_COLOR` and
+    /// `CLICOLOR_FORCE`.
+    #[default]
+    Auto,
+    /// Always emit escape sequences.
+    Always,
+    /// Never emit escape sequences.
+    Never,
+}
+
+impl ColorChoice {
+    /// Resolves this choice into whether colors should be emitted.
+    pub fn resolve(self) -> bool {
+        match self {
+            ColorChoice::Auto => crate::term::colors_enabled(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+    /// Resolves this choice and the color depth detected or given with
+    /// `--color-depth` into the depth output is rendered at.
+    ///
+    /// `Always` falls back to the 16 basic colors where the terminal
+    /// seems to support none, such as with `TERM=dumb`.
+    pub fn resolve_depth(self, depth: ColorDepth) -> ColorDepth {
+        match self {
+            _ if !self.resolve() => ColorDepth::None,
+            ColorChoice::Always if depth == ColorDepth::None => ColorDepth::Ansi16,
+            _ => depth,
+        }
+    }
+}
+
+/// Values accepted by `--color-depth`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, ValueEnum)]
+pub enum ColorDepthArg {
+    /// Detect the depth from the environment.
+    #[default]
+    Auto,
+    /// 24-bit RGB colors.
+    Truecolor,
+    /// The xterm 256-color palette.
+    #[value(name = "256")]
+    Ansi256,
+    /// The 16 basic ANSI colors.
+    #[value(name = "16")]
+    Ansi16,
+}
+
+impl ColorDepthArg {
+    /// Resolves this choice into a concrete [`ColorDepth`].
+    pub fn resolve(self) -> ColorDepth {
+        match self {
+            ColorDepthArg::Auto => ColorDepth::detect(),
+            ColorDepthArg::Truecolor => ColorDepth::TrueColor,
+            ColorDepthArg::Ansi256 => ColorDepth::Ansi256,
+            ColorDepthArg::Ansi16 => ColorDepth::Ansi16,
+        }
+    }
+}
+
+/// Values accepted by `--ansi`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum AnsiModeArg {
+    /// Remove escape sequences before matching.
+    Strip,
+    /// Match the visible text only and keep the input's colors.
+    Preserve,
+}
+
+impl AnsiModeArg {
+    /// Resolves this choice into an [`AnsiMode`].
+    pub fn resolve(self) -> AnsiMode {
+        match self {
+            AnsiModeArg::Strip => AnsiMode::Strip,
+            AnsiModeArg::Preserve => AnsiMode::Preserve,
+        }
+    }
+}
+
+/// Values accepted by `--output`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormatArg {
+    /// ANSI escape sequences for terminals.
+    #[default]
+    Ansi,
+    /// HTML for web pages and documents.
+    Html,
+    /// A JSON array of lines and their spans.
+    Json,
+    /// One JSON record per line, with its spans.
+    Jsonl,
+    /// An SVG image, as a terminal would display the output.
+    Svg,
+}
+
+impl CliArgs {
+    /// Resolves `--output` and its options into an [`OutputFormat`].
+    pub fn output_format(&self) -> OutputFormat {
+        match self.output {
+            OutputFormatArg::Ansi => OutputFormat::Ansi,
+            OutputFormatArg::Html => OutputFormat::Html(HtmlOptions {
+                classes: self.html_classes,
+                standalone: self.html_standalone,
+            }),
+            OutputFormatArg::Json => OutputFormat::Json,
+            OutputFormatArg::Jsonl => OutputFormat::JsonLines,
+            OutputFormatArg::Svg => {
+                let defaults = SvgOptions::default();
+                OutputFormat::Svg(SvgOptions {
+                    font_family: self.svg_font.clone().unwrap_or(defaults.font_family),
+                    font_size: self.svg_font_size.unwrap_or(defaults.font_size),
+                    background: self.svg_background.clone(),
+                    columns: self.svg_width,
+                })
+            }
+        }
+    }
+}
+
+/// The name of a project-level configuration file.
+const PROJECT_CONFIG: &str = ".highlite.yaml";
+
+/// Returns highlite's configuration directory: `highlite` under
+/// `$XDG_CONFIG_HOME`, or under `~/.config` if that is unset.
+pub(crate) fn config_home() -> Option<PathBuf> {
+    config_home_from(env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME"))
+}
+
+/// Determines highlite's configuration directory from the values of
+/// `XDG_CONFIG_HOME` and `HOME`. Relative values of `XDG_CONFIG_HOME` are
+/// ignored, as the XDG specification requires.
+pub(crate) fn config_home_from(xdg_config_home: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+    let base = match xdg_config_home.map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path,
+        _ => PathBuf::from(home?).join(".config"),
+    };
+    Some(base.join("highlite"))
+}
+
+/// Looks for a default configuration file, used when neither `--config`
+/// nor `--preset` is given.
+///
+/// The first of these wins:
+///
+/// 1. the path in `$HIGHLITE_CONFIG`
+/// 2. `.highlite.yaml` in the current directory or one of its parents, up
+///    to the root of the enclosing git repository
+/// 3. `config.yaml` in highlite's directory under `$XDG_CONFIG_HOME`
+pub fn discover_config() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok();
+    discover_config_from(env::var_os("HIGHLITE_CONFIG"), cwd.as_deref(), config_home())
+}
+
+/// Looks for a default configuration file given the value of
+/// `HIGHLITE_CONFIG`, the current directory and highlite's configuration
+/// directory. See [`discover_config`].
+///
+/// A path from `HIGHLITE_CONFIG` is returned even if it does not exist,
+/// so that loading it reports the error.
+pub(crate) fn discover_config_from(
+    env_config: Option<OsString>,
+    cwd: Option<&Path>,
+    config_home: Option<PathBuf>,
+) -> Option<PathBuf> {
+    if let Some(path) = env_config.filter(|path| !path.is_empty()) {
+        return Some(path.into());
+    }
+
+    if let Some(cwd) = cwd {
+        for dir in cwd.ancestors() {
+            let candidate = dir.join(PROJECT_CONFIG);
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+            // 到达仓库根目录后停止向上查找
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+    }
+
+    config_home
+        .map(|dir| dir.join("config.yaml"))
+        .filter(|path| path.is_file())
 }
 
 /// Deserialized representation of a configuration file.
 ///
-/// A configuration file may include additional configuration files
-/// and/or define a list of highlighting rules.
+/// A configuration file may extend a built-in preset, include additional
+/// configuration files, define a palette of named colors, a list of
+/// highlighting rules and/or named contexts those rules can push, and
+/// mappings used to detect a preset from the input.
+///
+/// Inherited rules (from the preset and included files) can be removed or
+/// restyled by id, and a rule whose id matches an inherited rule replaces
+/// it in place.
 #[derive(Debug, Deserialize)]
 pub(crate) struct FileConfig {
-    /// Paths to other configuration files to include.
+    /// A short description, shown by `list-presets` for user presets.
+    pub(crate) description: Option<String>,
+
+    /// A built-in preset whose rules and contexts this file builds on.
+    pub(crate) extends: Option<String>,
+
+    /// Paths to other configuration files to include. An entry of the
+    /// form `preset:<name>` includes a built-in preset.
     pub(crate) include: Option<Vec<String>>,
 
+    /// Ids of inherited rules to remove.
+    pub(crate) remove: Option<Vec<String>>,
+
+    /// Style changes to inherited rules, by id.
+    pub(crate) overrides: Option<HashMap<String, RuleOverride>>,
+
+    /// Named colors that rules can reference with `{ ref: <name> }`.
+    pub(crate) palette: Option<HashMap<String, Color>>,
+
     /// Highlighting rules defined in this file.
     pub(crate) rules: Option<Vec<Rule>>,
+
+    /// Named contexts that rules can push.
+    pub(crate) contexts: Option<BTreeMap<String, Context>>,
+
+    /// Mappings from input files to presets.
+    pub(crate) detect: Option<DetectConfig>,
+}
+
+/// Style fields replacing those of an inherited rule. Fields that are not
+/// given keep the rule's value.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RuleOverride {
+    pub(crate) kind: Option<String>,
+    pub(crate) color: Option<Color>,
+    pub(crate) background: Option<Color>,
+    pub(crate) style: Option<Style>,
+}
+
+impl RuleOverride {
+    fn apply(self, rule: &mut Rule) {
+        if let Some(kind) = self.kind {
+            rule.kind = Some(kind);
+        }
+        if let Some(color) = self.color {
+            rule.color = Some(color);
+            rule.style.foreground = None;
+        }
+        if let Some(background) = self.background {
+            rule.background = Some(background);
+            rule.style.background = None;
+        }
+        if let Some(style) = self.style {
+            rule.style = style;
+        }
+    }
+}
+
+/// Rules, contexts, palette and detection mappings accumulated while
+/// loading a configuration file and its includes.
+#[derive(Debug, Default)]
+struct LoadedConfig {
+    rules: Vec<Rule>,
+    contexts: BTreeMap<String, Context>,
+    palette: HashMap<String, Color>,
+    detect: DetectConfig,
+}
+
+impl LoadedConfig {
+    /// Appends the rules and contexts of a built-in preset.
+    fn extend_with_preset(&mut self, name: &str) -> anyhow::Result<()> {
+        let preset = crate::preset::get_preset(name)?;
+        self.rules.extend(preset.rules);
+        self.contexts.extend(preset.contexts);
+        Ok(())
+    }
+
+    /// Returns the index of the top-level rule with the given id.
+    fn position(&self, id: &str) -> Option<usize> {
+        self.rules.iter().position(|rule| rule.id.as_deref() == Some(id))
+    }
 }
 
 /// Loads highlighting rules from a YAML configuration file.
 ///
 /// This function resolves `include` directives recursively and prevents
-/// duplicate loading of the same file.
+/// duplicate loading of the same file. Palettes of all loaded files are
+/// merged, and palette references in rule colors are resolved against the
+/// merged palette.
 ///
 /// # Examples
 ///
@@ -76,17 +493,107 @@ pub(crate) struct FileConfig {
 ///
 /// # Errors
 ///
-/// Returns an error if a file cannot be read, parsed, or resolved.
+/// Returns an error if a file cannot be read, parsed, or resolved, or if
+/// a rule references an undefined palette color.
 pub fn load_rules_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Rule>> {
+    Ok(load_rule_set_from_file(path)?.rules)
+}
+
+/// Loads highlighting rules and the contexts they can push from a YAML
+/// configuration file.
+///
+/// Includes and palettes are handled as in [`load_rules_from_file`].
+/// Contexts of a file replace contexts of the same name defined in the
+/// files it includes.
+///
+/// # Errors
+///
+/// Returns an error if a file cannot be read, parsed, or resolved, or if
+/// a rule or context references an undefined palette color.
+pub fn load_rule_set_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<RuleSet> {
+    Ok(load_config(path)?.rule_set)
+}
+
+/// Reads the `description` of a configuration file, without resolving its
+/// includes.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or parsed.
+pub(crate) fn read_description(path: &Path) -> anyhow::Result<Option<String>> {
+    let text = fs::read_to_string(path)?;
+    let file_config: FileConfig = serde_yml::from_str(&text)?;
+    Ok(file_config.description)
+}
+
+/// A fully loaded configuration file.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// The rules and contexts of the file and its includes.
+    pub rule_set: RuleSet,
+
+    /// The `detect` mappings of the file and its includes.
+    pub detect: DetectConfig,
+}
+
+/// Loads a YAML configuration file, including its `detect` section.
+///
+/// Rules and contexts are loaded as in [`load_rule_set_from_file`].
+/// Detection mappings of a file replace those of the files it includes.
+///
+/// # Errors
+///
+/// Returns an error if a file cannot be read, parsed, or resolved, or if
+/// a rule or context references an undefined palette color.
+pub fn load_config<P: AsRef<Path>>(path: P) -> anyhow::Result<Config> {
     let mut loaded_files = HashSet::new();
-    load_rules_recursive(path.as_ref(), &mut loaded_files)
+    let LoadedConfig { mut rules, mut contexts, palette, detect } =
+        load_rules_recursive(path.as_ref(), &mut loaded_files)?;
+
+    resolve_palette(&mut rules, &palette)?;
+    for (name, context) in contexts.iter_mut() {
+        for color in context.colors_mut() {
+            *color = color
+                .resolve(&palette)
+                .map_err(|e| anyhow::anyhow!("{} in context '{}'", e, name))?;
+        }
+        resolve_palette(&mut context.rules, &palette)?;
+    }
+
+    Ok(Config {
+        rule_set: RuleSet { rules, contexts },
+        detect,
+    })
+}
+
+/// Replaces every palette reference in `rules` with the referenced color.
+///
+/// # Errors
+///
+/// Returns an error naming the rule if a reference is undefined.
+fn resolve_palette(rules: &mut [Rule], palette: &HashMap<String, Color>) -> anyhow::Result<()> {
+    for rule in rules {
+        let label = rule.label().to_string();
+        for color in rule.colors_mut() {
+            *color = color
+                .resolve(palette)
+                .map_err(|e| anyhow::anyhow!("{} in rule '{}'", e, label))?;
+        }
+    }
+    Ok(())
 }
 
 /// Recursively loads highlighting rules from a configuration file.
 ///
 /// This function parses the given YAML configuration file, resolves any
 /// `include` directives relative to the file's parent directory, and
-/// accumulates all discovered rules into a single list.
+/// accumulates all discovered rules into a single list. Palette entries of
+/// a file override those of the files it includes.
+///
+/// The rules of the preset named by `extends` come first, followed by
+/// those of the included files. The file's `remove` and `overrides`
+/// entries then apply to these inherited rules, before its own rules are
+/// added.
 ///
 /// To prevent infinite recursion and duplicate rule loading, each
 /// configuration file is canonicalized and tracked in the `loaded` set.
@@ -106,31 +613,76 @@ pub fn load_rules_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Rule>
 /// * The file cannot be read
 /// * The YAML content cannot be parsed
 /// * An included file cannot be resolved or loaded
+/// * An extended or included preset does not exist
+/// * A removed or overridden rule id is not inherited
 fn load_rules_recursive(
     path: &Path,
     loaded: &mut HashSet<String>,
-) -> anyhow::Result<Vec<Rule>> {
+) -> anyhow::Result<LoadedConfig> {
     let canonical_path = fs::canonicalize(path)?.to_string_lossy().to_string();
 
     if !loaded.insert(canonical_path) {
-        return Ok(vec![]);
+        return Ok(LoadedConfig::default());
     }
 
     let text = fs::read_to_string(path)?;
     let file_config: FileConfig = serde_yml::from_str(&text)?;
-    let mut all_rules = Vec::new();
+    let mut config = LoadedConfig::default();
+
+    if let Some(preset) = file_config.extends {
+        config.extend_with_preset(&preset)?;
+    }
 
     if let Some(includes) = file_config.include {
         let parent_dir = path.parent().unwrap_or_else(|| Path::new("."));
         for inc_path in includes {
+            if let Some(preset) = inc_path.strip_prefix("preset:") {
+                config.extend_with_preset(preset)?;
+                continue;
+            }
             let full_path = parent_dir.join(inc_path);
-            all_rules.append(&mut load_rules_recursive(&full_path, loaded)?);
+            let included = load_rules_recursive(&full_path, loaded)?;
+            config.rules.extend(included.rules);
+            config.contexts.extend(included.contexts);
+            config.palette.extend(included.palette);
+            config.detect.extend(included.detect);
         }
     }
 
-    if let Some(current_rules) = file_config.rules {
-        all_rules.extend(current_rules);
+    if let Some(palette) = file_config.palette {
+        config.palette.extend(palette);
+    }
+
+    // 删除、覆盖继承来的规则
+    for id in file_config.remove.unwrap_or_default() {
+        let idx = config.position(&id).ok_or_else(|| {
+            anyhow::anyhow!("Cannot remove unknown rule '{}' in {}", id, path.display())
+        })?;
+        config.rules.remove(idx);
+    }
+
+    for (id, rule_override) in file_config.overrides.unwrap_or_default() {
+        let idx = config.position(&id).ok_or_else(|| {
+            anyhow::anyhow!("Cannot override unknown rule '{}' in {}", id, path.display())
+        })?;
+        rule_override.apply(&mut config.rules[idx]);
+    }
+
+    // id 与继承的规则相同时原地替换，其余追加到末尾
+    for rule in file_config.rules.unwrap_or_default() {
+        match rule.id.as_deref().and_then(|id| config.position(id)) {
+            Some(idx) => config.rules[idx] = rule,
+            None => config.rules.push(rule),
+        }
+    }
+
+    if let Some(contexts) = file_config.contexts {
+        config.contexts.extend(contexts);
+    }
+
+    if let Some(detect) = file_config.detect {
+        config.detect.extend(detect);
     }
 
-    Ok(all_rules)
+    Ok(config)
 }
\ No newline at end of file
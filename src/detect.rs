@@ -0,0 +1,150 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Built-in file extension mappings, as `(extension, preset)` pairs.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("c", "cpp"),
+    ("h", "cpp"),
+    ("cc", "cpp"),
+    ("cpp", "cpp"),
+    ("cxx", "cpp"),
+    ("c++", "cpp"),
+    ("hh", "cpp"),
+    ("hpp", "cpp"),
+    ("hxx", "cpp"),
+    ("ino", "cpp"),
+    ("json", "json"),
+    ("jsonl", "json"),
+    ("ndjson", "json"),
+    ("geojson", "json"),
+    ("log", "logs"),
+];
+
+/// Built-in file name mappings, as `(file name, preset)` pairs.
+const FILENAMES: &[(&str, &str)] = &[
+    ("syslog", "logs"),
+    ("messages", "logs"),
+    ("dmesg", "logs"),
+];
+
+/// Content heuristics applied to the first non-empty lines, as
+/// `(pattern, preset, first line only)` tuples. The earliest line matching
+/// a pattern decides.
+static CONTENT: Lazy<Vec<(Regex, &'static str, bool)>> = Lazy::new(|| {
+    [
+        // JSON 对象或数组（排除 "[main]" 这类日志前缀）。单独一行的 "{"
+        // 也可能是代码块的开始，因此只检查第一行
+        (r#"^\s*(\{\s*("|\}|$)|\[\s*([\[\{"]|$))"#, "json", true),
+        (r#"^\s*#\s*(include\s*[<"]|pragma\s+once)"#, "cpp", false),
+        // syslog 的优先级前缀或 "Mar 21 10:15:44" 格式的时间戳
+        (r"^<\d{1,3}>|^[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2} ", "logs", false),
+        (r"^\[?\d{4}-\d{2}-\d{2}[ T]\d{2}:\d{2}", "logs", false),
+    ]
+    .into_iter()
+    .map(|(pattern, preset, first_only)| (Regex::new(pattern).unwrap(), preset, first_only))
+    .collect()
+});
+
+/// How many lines of the input content heuristics look at.
+const SNIFF_LINES: usize = 5;
+
+/// User-defined mappings from input files to presets, read from the
+/// `detect` section of a configuration file.
+///
+/// User mappings are consulted before the built-in ones.
+///
+/// # YAML
+///
+/// ```yaml
+/// detect:
+///   extensions: { ipp: cpp, trace: backend }
+///   filenames: { Jenkinsfile: groovy }
+///   interpreters: { python3: python }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DetectConfig {
+    /// File extensions (without the dot, matched case-insensitively).
+    #[serde(default)]
+    pub extensions: HashMap<String, String>,
+
+    /// Exact file names.
+    #[serde(default)]
+    pub filenames: HashMap<String, String>,
+
+    /// Interpreters named on a `#!` line, such as `python3` in
+    /// `#!/usr/bin/env python3`.
+    #[serde(default)]
+    pub interpreters: HashMap<String, String>,
+}
+
+impl DetectConfig {
+    /// Adds the mappings of `other`, replacing existing entries.
+    pub(crate) fn extend(&mut self, other: DetectConfig) {
+        self.extensions.extend(other.extensions);
+        self.filenames.extend(other.filenames);
+        self.interpreters.extend(other.interpreters);
+    }
+}
+
+/// Picks a preset for an input from its file name and first bytes.
+///
+/// The checks run in this order, and the first one to give a result wins:
+///
+/// 1. the file name
+/// 2. the file extension
+/// 3. the interpreter of a `#!` line
+/// 4. content heuristics on the first non-empty lines, such as a leading
+///    `{` on the first one for JSON or a syslog prefix for logs
+///
+/// Returns `None` if nothing matches.
+pub fn detect_preset(path: Option<&Path>, head: &[u8], config: &DetectConfig) -> Option<String> {
+    let lookup = |user: &HashMap<String, String>, builtin: &[(&str, &str)], key: &str| {
+        user.get(key)
+            .cloned()
+            .or_else(|| builtin.iter().find(|(k, _)| *k == key).map(|(_, preset)| preset.to_string()))
+    };
+
+    if let Some(path) = path {
+        if let Some(name) = path.file_name().and_then(|name| name.to_str())
+            && let Some(preset) = lookup(&config.filenames, FILENAMES, name)
+        {
+            return Some(preset);
+        }
+        if let Some(ext) = path.extension().and_then(|ext| ext.to_str())
+            && let Some(preset) = lookup(&config.extensions, EXTENSIONS, &ext.to_ascii_lowercase())
+        {
+            return Some(preset);
+        }
+    }
+
+    let head = String::from_utf8_lossy(head);
+
+    if let Some(interpreter) = head.lines().next().and_then(shebang_interpreter)
+        && let Some(preset) = config.interpreters.get(interpreter)
+    {
+        return Some(preset.clone());
+    }
+
+    let lines = head.lines().filter(|line| !line.trim().is_empty()).take(SNIFF_LINES);
+    lines.enumerate().find_map(|(i, line)| {
+        CONTENT
+            .iter()
+            .find(|(regex, _, first_only)| (i == 0 || !first_only) && regex.is_match(line))
+            .map(|(_, preset, _)| preset.to_string())
+    })
+}
+
+/// Returns the interpreter named on a `#!` line: the program's file name,
+/// or its first argument for `env`.
+fn shebang_interpreter(line: &str) -> Option<&str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        words.find(|word| !word.starts_with('-'))
+    } else {
+        Some(program)
+    }
+}
@@ -0,0 +1,347 @@
+use crate::rules::{Attribute, Style};
+use crate::term::ColorDepth;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+mod svg;
+
+pub use svg::{DEFAULT_FONT_FAMILY, SvgOptions};
+
+/// The format highlighted text is rendered in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// ANSI escape sequences, for terminals.
+    #[default]
+    Ansi,
+    /// HTML `<span>` elements inside a `<pre>` element.
+    Html(HtmlOptions),
+    /// A JSON array with a record of each line and its spans.
+    Json,
+    /// A JSON record of each line and its spans, one per line.
+    JsonLines,
+    /// An SVG image of the text as a terminal displays it.
+    Svg(SvgOptions),
+}
+
+impl OutputFormat {
+    /// Returns `true` for the formats made of JSON records, which are
+    /// built from [`HighlightingEngine::spans`] instead of markup.
+    ///
+    /// [`HighlightingEngine::spans`]: crate::highlight::HighlightingEngine::spans
+    pub fn is_json(&self) -> bool {
+        matches!(self, OutputFormat::Json | OutputFormat::JsonLines)
+    }
+
+    /// Returns `true` for the formats that cannot carry ANSI escape
+    /// sequences. They turn the escape sequences of the input into styles
+    /// beneath the rules' styles instead.
+    pub fn interprets_escapes(&self) -> bool {
+        *self != OutputFormat::Ansi
+    }
+}
+
+/// Options of the HTML output format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HtmlOptions {
+    /// Style spans with CSS classes and a generated stylesheet instead of
+    /// inline `style` attributes.
+    pub classes: bool,
+
+    /// Produce a complete HTML document instead of a fragment.
+    pub standalone: bool,
+}
+
+/// Colors of unstyled text in formats that are not displayed in a
+/// terminal, used when the theme has no `default` style.
+const DEFAULT_FOREGROUND: (u8, u8, u8) = (0xd4, 0xd4, 0xd4);
+const DEFAULT_BACKGROUND: (u8, u8, u8) = (0x1e, 0x1e, 0x1e);
+
+/// Turns styles and text into the markup of an output format.
+///
+/// Styled text is written as the markup returned by [`Formatter::open`],
+/// the text itself, and [`Formatter::close`].
+pub(crate) trait Formatter {
+    /// Returns the markup starting text in `style`, or an empty string if
+    /// the style changes nothing.
+    fn open(&self, style: &Style) -> anyhow::Result<String>;
+
+    /// Returns the markup ending styled text.
+    fn close(&self) -> &'static str;
+
+    /// Appends text to `output`, escaped as the format requires.
+    fn push_text(&self, output: &mut String, text: &str);
+
+    /// Returns the markup preceding all lines.
+    fn header(&self) -> anyhow::Result<String> {
+        Ok(String::new())
+    }
+
+    /// Returns the markup following all lines.
+    fn footer(&self) -> String {
+        String::new()
+    }
+
+    /// Returns `true` if the format adds no markup at all, so input can be
+    /// passed through as is.
+    fn is_plain(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if the format needs all lines before it can write
+    /// anything. The rendered lines are then passed to
+    /// [`Formatter::document`] as a whole.
+    fn buffered(&self) -> bool {
+        false
+    }
+
+    /// Turns all rendered lines into the document of a buffered format.
+    fn document(&self, body: &str) -> anyhow::Result<String> {
+        Ok(body.to_string())
+    }
+}
+
+/// Creates the formatter for `format`.
+///
+/// `depth` only applies to ANSI output. `default` is the theme's style for
+/// unstyled text, used by formats that set their own background. `styles`
+/// are all styles the engine uses.
+///
+/// # Errors
+///
+/// Returns an error if a color of `default` or `styles` cannot be
+/// converted.
+pub(crate) fn formatter(
+    format: &OutputFormat,
+    depth: ColorDepth,
+    default: Option<&Style>,
+    styles: &[Style],
+) -> anyhow::Result<Box<dyn Formatter>> {
+    Ok(match format {
+        OutputFormat::Ansi => Box::new(Ansi { depth }),
+        OutputFormat::Html(options) => Box::new(Html::new(*options, default, styles)?),
+        OutputFormat::Svg(options) => Box::new(svg::Svg::new(options.clone(), default)?),
+        // JSON 记录由 spans 生成，不需要标记
+        OutputFormat::Json | OutputFormat::JsonLines => Box::new(Ansi { depth: ColorDepth::None }),
+    })
+}
+
+/// ANSI escape sequences reduced to a color depth.
+struct Ansi {
+    depth: ColorDepth,
+}
+
+impl Formatter for Ansi {
+    fn open(&self, style: &Style) -> anyhow::Result<String> {
+        style.to_ansi(self.depth)
+    }
+
+    fn close(&self) -> &'static str {
+        "\x1b[0m"
+    }
+
+    fn push_text(&self, output: &mut String, text: &str) {
+        output.push_str(text);
+    }
+
+    fn is_plain(&self) -> bool {
+        self.depth == ColorDepth::None
+    }
+}
+
+/// HTML with inline styles or CSS classes.
+struct Html {
+    options: HtmlOptions,
+    foreground: (u8, u8, u8),
+    background: (u8, u8, u8),
+    /// The colors with classes in the stylesheet. Other colors, such as
+    /// those of escape sequences in the input, are styled inline.
+    colors: BTreeSet<(u8, u8, u8)>,
+}
+
+/// A style reduced to what HTML output needs. Reversed colors are already
+/// swapped.
+#[derive(Default)]
+struct CssStyle {
+    foreground: Option<(u8, u8, u8)>,
+    background: Option<(u8, u8, u8)>,
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+}
+
+impl Html {
+    fn new(options: HtmlOptions, default: Option<&Style>, styles: &[Style]) -> anyhow::Result<Self> {
+        let color = |color: Option<&crate::rules::Color>, fallback| color.map_or(Ok(fallback), |c| c.to_rgb());
+        let foreground = color(default.and_then(|s| s.foreground.as_ref()), DEFAULT_FOREGROUND)?;
+        let background = color(default.and_then(|s| s.background.as_ref()), DEFAULT_BACKGROUND)?;
+
+        let mut colors = BTreeSet::from([foreground, background]);
+        for color in styles.iter().flat_map(|style| style.foreground.iter().chain(&style.background)) {
+            colors.insert(color.to_rgb()?);
+        }
+        Ok(Self { options, foreground, background, colors })
+    }
+
+    fn css_style(&self, style: &Style) -> anyhow::Result<CssStyle> {
+        let mut css = CssStyle {
+            foreground: style.foreground.as_ref().map(|c| c.to_rgb()).transpose()?,
+            background: style.background.as_ref().map(|c| c.to_rgb()).transpose()?,
+            ..Default::default()
+        };
+        for attr in &style.attributes {
+            match attr {
+                Attribute::Bold => css.bold = true,
+                Attribute::Dim => css.dim = true,
+                Attribute::Italic => css.italic = true,
+                Attribute::Underline => css.underline = true,
+                Attribute::Strikethrough => css.strikethrough = true,
+                // 反色在 HTML 中没有对应属性，直接交换前景色和背景色
+                Attribute::Reverse => {
+                    let foreground = css.background.unwrap_or(self.background);
+                    css.background = Some(css.foreground.unwrap_or(self.foreground));
+                    css.foreground = Some(foreground);
+                }
+            }
+        }
+        Ok(css)
+    }
+
+    /// The inline declarations of the element wrapping all lines.
+    fn pre_declarations(&self) -> String {
+        format!("color: {}; background-color: {}", hex(self.foreground), hex(self.background))
+    }
+
+    /// Generates the stylesheet for the classes of the engine's styles.
+    ///
+    /// Each color gets a foreground and a background class, since
+    /// composing styles of several layers and reversing them can move any
+    /// color to either side.
+    fn stylesheet(&self) -> String {
+        let mut css = format!(".highlite {{ {}; }}\n", self.pre_declarations());
+        for &color in &self.colors {
+            let _ = writeln!(css, ".hl-fg-{0} {{ color: #{0}; }}", hex_digits(color));
+            let _ = writeln!(css, ".hl-bg-{0} {{ background-color: #{0}; }}", hex_digits(color));
+        }
+        css.push_str(
+            ".hl-bold { font-weight: bold; }\n\
+             .hl-dim { opacity: 0.6; }\n\
+             .hl-italic { font-style: italic; }\n\
+             .hl-underline { text-decoration: underline; }\n\
+             .hl-strike { text-decoration: line-through; }\n\
+             .hl-underline-strike { text-decoration: underline line-through; }\n",
+        );
+        css
+    }
+}
+
+impl Formatter for Html {
+    fn open(&self, style: &Style) -> anyhow::Result<String> {
+        let css = self.css_style(style)?;
+        let decoration = match (css.underline, css.strikethrough) {
+            (true, true) => Some(("underline line-through", "underline-strike")),
+            (true, false) => Some(("underline", "underline")),
+            (false, true) => Some(("line-through", "strike")),
+            (false, false) => None,
+        };
+
+        // 样式表中没有类的颜色使用内联样式
+        let color_class = |prefix: &str, c| self.colors.contains(&c).then(|| format!("hl-{}-{}", prefix, hex_digits(c)));
+
+        // (内联样式, 类名)
+        let parts: Vec<(String, Option<String>)> = [
+            css.foreground.map(|c| (format!("color: {}", hex(c)), color_class("fg", c))),
+            css.background.map(|c| (format!("background-color: {}", hex(c)), color_class("bg", c))),
+            css.bold.then(|| ("font-weight: bold".into(), Some("hl-bold".into()))),
+            css.dim.then(|| ("opacity: 0.6".into(), Some("hl-dim".into()))),
+            css.italic.then(|| ("font-style: italic".into(), Some("hl-italic".into()))),
+            decoration.map(|(value, class)| (format!("text-decoration: {}", value), Some(format!("hl-{}", class)))),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        let mut classes = Vec::new();
+        let mut declarations = Vec::new();
+        for (declaration, class) in &parts {
+            match class {
+                Some(class) if self.options.classes => classes.push(class.as_str()),
+                _ => declarations.push(declaration.as_str()),
+            }
+        }
+
+        let mut span = String::new();
+        if !classes.is_empty() {
+            let _ = write!(span, " class=\"{}\"", classes.join(" "));
+        }
+        if !declarations.is_empty() {
+            let _ = write!(span, " style=\"{}\"", declarations.join("; "));
+        }
+        Ok(if span.is_empty() { span } else { format!("<span{}>", span) })
+    }
+
+    fn close(&self) -> &'static str {
+        "</span>"
+    }
+
+    fn push_text(&self, output: &mut String, text: &str) {
+        push_escaped(output, text);
+    }
+
+    fn header(&self) -> anyhow::Result<String> {
+        let mut header = String::new();
+        let stylesheet = self.options.classes.then(|| self.stylesheet());
+
+        if self.options.standalone {
+            header.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>highlite</title>\n");
+            if let Some(stylesheet) = &stylesheet {
+                let _ = write!(header, "<style>\n{}</style>\n", stylesheet);
+            }
+            let _ = writeln!(header, "</head>\n<body style=\"{}\">", self.pre_declarations());
+        } else if let Some(stylesheet) = &stylesheet {
+            let _ = write!(header, "<style>\n{}</style>\n", stylesheet);
+        }
+
+        if self.options.classes {
+            header.push_str("<pre class=\"highlite\">");
+        } else {
+            let _ = write!(header, "<pre class=\"highlite\" style=\"{}\">", self.pre_declarations());
+        }
+        Ok(header)
+    }
+
+    fn footer(&self) -> String {
+        if self.options.standalone {
+            "</pre>\n</body>\n</html>\n".to_string()
+        } else {
+            "</pre>\n".to_string()
+        }
+    }
+}
+
+/// Appends text to `output` with the characters special in HTML and SVG
+/// text escaped.
+fn push_escaped(output: &mut String, text: &str) {
+    let mut last = 0;
+    for (i, c) in text.match_indices(['&', '<', '>']) {
+        output.push_str(&text[last..i]);
+        output.push_str(match c {
+            "&" => "&amp;",
+            "<" => "&lt;",
+            _ => "&gt;",
+        });
+        last = i + 1;
+    }
+    output.push_str(&text[last..]);
+}
+
+/// Formats a color as `#rrggbb`.
+fn hex(color: (u8, u8, u8)) -> String {
+    format!("#{}", hex_digits(color))
+}
+
+/// Formats a color as `rrggbb`.
+fn hex_digits((r, g, b): (u8, u8, u8)) -> String {
+    format!("{:02x}{:02x}{:02x}", r, g, b)
+}
@@ -0,0 +1,260 @@
+use super::{DEFAULT_BACKGROUND, DEFAULT_FOREGROUND, Formatter, hex, push_escaped};
+use crate::ansi::AnsiParser;
+use crate::rules::{Attribute, Color, Style};
+use crate::term::ColorDepth;
+use std::fmt::Write;
+use unicode_width::UnicodeWidthChar;
+
+/// Options of the SVG output format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SvgOptions {
+    /// The CSS font family of the text. It should name monospace fonts.
+    pub font_family: String,
+
+    /// The font size in pixels.
+    pub font_size: u32,
+
+    /// The background color. Defaults to the background of the theme's
+    /// `default` style.
+    pub background: Option<Color>,
+
+    /// The number of columns, after which lines wrap. Defaults to the
+    /// width of the longest line.
+    pub columns: Option<usize>,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        Self {
+            font_family: DEFAULT_FONT_FAMILY.to_string(),
+            font_size: 14,
+            background: None,
+            columns: None,
+        }
+    }
+}
+
+/// The font family used when none is given.
+pub const DEFAULT_FONT_FAMILY: &str = "ui-monospace, SFMono-Regular, Menlo, Consolas, 'DejaVu Sans Mono', monospace";
+
+/// The advance width of monospace fonts, relative to the font size.
+const CHAR_WIDTH: f64 = 0.6;
+/// The height of a row, relative to the font size.
+const LINE_HEIGHT: f64 = 1.4;
+/// Columns a tab advances to a multiple of.
+const TAB_WIDTH: usize = 8;
+
+type Rgb = (u8, u8, u8);
+
+/// An SVG image of the text as a terminal would display it.
+///
+/// Lines are rendered as 24-bit ANSI escape sequences first, which
+/// [`Formatter::document`] interprets with an [`AnsiParser`] to lay the
+/// text out on a grid of cells. Since the image is sized to the text,
+/// nothing can be written before the last line.
+pub(super) struct Svg {
+    options: SvgOptions,
+    foreground: Rgb,
+    background: Rgb,
+}
+
+/// A run of cells of one row in one style.
+struct Cells {
+    column: usize,
+    width: usize,
+    text: String,
+    style: Style,
+}
+
+impl Svg {
+    pub(super) fn new(options: SvgOptions, default: Option<&Style>) -> anyhow::Result<Self> {
+        let foreground = match default.and_then(|s| s.foreground.as_ref()) {
+            Some(color) => color.to_rgb()?,
+            None => DEFAULT_FOREGROUND,
+        };
+        let background = match options.background.as_ref().or(default.and_then(|s| s.background.as_ref())) {
+            Some(color) => color.to_rgb()?,
+            None => DEFAULT_BACKGROUND,
+        };
+        Ok(Self { options, foreground, background })
+    }
+
+    /// Lays out the lines of `body` on rows of at most `columns` cells,
+    /// wrapping longer lines.
+    fn layout(&self, body: &str) -> Vec<Vec<Cells>> {
+        let mut parser = AnsiParser::default();
+        let mut rows = Vec::new();
+
+        for line in body.lines() {
+            let mut row: Vec<Cells> = Vec::new();
+            let mut column = 0;
+            for piece in parser.parse(line) {
+                for c in piece.text.chars() {
+                    let width = match c {
+                        // 和终端一样，制表符不会越过最后一列
+                        '\t' => {
+                            let stop = TAB_WIDTH - column % TAB_WIDTH;
+                            self.options.columns.map_or(stop, |columns| stop.min(columns.saturating_sub(column)))
+                        }
+                        c => c.width().unwrap_or(0),
+                    };
+                    if width == 0 {
+                        continue;
+                    }
+                    if self.options.columns.is_some_and(|columns| column + width > columns && column > 0) {
+                        rows.push(std::mem::take(&mut row));
+                        column = 0;
+                    }
+                    match row.last_mut() {
+                        Some(cells) if cells.style == piece.style && cells.column + cells.width == column => {
+                            cells.width += width;
+                        }
+                        _ => row.push(Cells { column, width, text: String::new(), style: piece.style.clone() }),
+                    }
+                    let cells = row.last_mut().unwrap();
+                    if c == '\t' {
+                        // 制表符展开为空格
+                        cells.text.extend(std::iter::repeat_n(' ', width));
+                    } else {
+                        cells.text.push(c);
+                    }
+                    column += width;
+                }
+            }
+            rows.push(row);
+        }
+        rows
+    }
+
+    /// Returns the foreground and background of `style`, with reversed
+    /// colors swapped. The background is `None` if it is the default.
+    fn colors(&self, style: &Style) -> anyhow::Result<(Rgb, Option<Rgb>)> {
+        let rgb = |color: &Option<Color>| color.as_ref().map(|c| c.to_rgb()).transpose();
+        let (foreground, background) = (rgb(&style.foreground)?, rgb(&style.background)?);
+        Ok(if style.attributes.contains(&Attribute::Reverse) {
+            (background.unwrap_or(self.background), Some(foreground.unwrap_or(self.foreground)))
+        } else {
+            (foreground.unwrap_or(self.foreground), background)
+        })
+    }
+}
+
+impl Formatter for Svg {
+    fn open(&self, style: &Style) -> anyhow::Result<String> {
+        style.to_ansi(ColorDepth::TrueColor)
+    }
+
+    fn close(&self) -> &'static str {
+        "\x1b[0m"
+    }
+
+    fn push_text(&self, output: &mut String, text: &str) {
+        output.push_str(text);
+    }
+
+    fn buffered(&self) -> bool {
+        true
+    }
+
+    fn document(&self, body: &str) -> anyhow::Result<String> {
+        let rows = self.layout(body);
+        let columns = self.options.columns.unwrap_or_else(|| {
+            rows.iter().filter_map(|row| row.last()).map(|cells| cells.column + cells.width).max().unwrap_or(0)
+        });
+
+        let size = self.options.font_size as f64;
+        let (cell_width, row_height, padding) = (size * CHAR_WIDTH, size * LINE_HEIGHT, size);
+        let width = padding * 2.0 + columns as f64 * cell_width;
+        let height = padding * 2.0 + rows.len() as f64 * row_height;
+
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">",
+            w = num(width),
+            h = num(height)
+        );
+        svg.push_str("<style>text { font-family: ");
+        push_escaped(&mut svg, &self.options.font_family);
+        let _ = writeln!(svg, "; font-size: {}px; white-space: pre; }}</style>", self.options.font_size);
+        let _ = writeln!(
+            svg,
+            "<rect width=\"100%\" height=\"100%\" rx=\"{}\" fill=\"{}\"/>",
+            num(size / 2.0),
+            hex(self.background)
+        );
+
+        for (i, row) in rows.iter().enumerate() {
+            let top = padding + i as f64 * row_height;
+            // 背景色在前，文字在上
+            for cells in row {
+                if let (_, Some(background)) = self.colors(&cells.style)? {
+                    let _ = writeln!(
+                        svg,
+                        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+                        num(padding + cells.column as f64 * cell_width),
+                        num(top),
+                        num(cells.width as f64 * cell_width),
+                        num(row_height),
+                        hex(background)
+                    );
+                }
+            }
+
+            // 只含空白且没有修饰的文字无需绘制
+            let cells: Vec<&Cells> = row
+                .iter()
+                .filter(|cells| !cells.text.trim().is_empty() || !cells.style.attributes.is_empty())
+                .collect();
+            if cells.is_empty() {
+                continue;
+            }
+            // 基线大致位于行高的四分之三处
+            let _ = write!(svg, "<text y=\"{}\">", num(top + row_height * 0.75));
+            for cells in cells {
+                let (foreground, _) = self.colors(&cells.style)?;
+                let _ = write!(
+                    svg,
+                    "<tspan x=\"{}\" fill=\"{}\"",
+                    num(padding + cells.column as f64 * cell_width),
+                    hex(foreground)
+                );
+                let attributes = &cells.style.attributes;
+                for attr in attributes {
+                    svg.push_str(match attr {
+                        Attribute::Bold => " font-weight=\"bold\"",
+                        Attribute::Dim => " opacity=\"0.6\"",
+                        Attribute::Italic => " font-style=\"italic\"",
+                        Attribute::Underline | Attribute::Strikethrough | Attribute::Reverse => "",
+                    });
+                }
+                // 下划线和删除线共用一个属性
+                let decoration = match (
+                    attributes.contains(&Attribute::Underline),
+                    attributes.contains(&Attribute::Strikethrough),
+                ) {
+                    (true, true) => Some("underline line-through"),
+                    (true, false) => Some("underline"),
+                    (false, true) => Some("line-through"),
+                    (false, false) => None,
+                };
+                if let Some(decoration) = decoration {
+                    let _ = write!(svg, " text-decoration=\"{}\"", decoration);
+                }
+                svg.push('>');
+                push_escaped(&mut svg, &cells.text);
+                svg.push_str("</tspan>");
+            }
+            svg.push_str("</text>\n");
+        }
+
+        svg.push_str("</svg>\n");
+        Ok(svg)
+    }
+}
+
+/// Formats a length with at most two decimals.
+fn num(value: f64) -> String {
+    let value = format!("{:.2}", value);
+    value.trim_end_matches('0').trim_end_matches('.').to_string()
+}
@@ -1,45 +1,378 @@
-use regex::Regex;
+use crate::ansi::{AnsiMode, StyledLine, StyledSpan};
+use crate::format::{Formatter, OutputFormat};
+use crate::rules::{Context, Rule, RuleSet, Scope, Style};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use crate::term::ColorDepth;
+use crate::theme::Theme;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex, RegexSet};
+use serde::Serialize;
+
+/// Options controlling how a [`HighlightingEngine`] compiles its rules.
+#[derive(Debug, Clone, Default)]
+pub struct EngineOptions {
+    /// Force all rules to match case-insensitively, regardless of their
+    /// individual `ignore_case` settings.
+    pub ignore_case: bool,
+
+    /// The color depth escape sequences are generated for.
+    pub color_depth: ColorDepth,
+
+    /// The format lines are rendered in.
+    pub output: OutputFormat,
+
+    /// How escape sequences in the input are handled. If `None`, they are
+    /// passed through in ANSI output and preserved in other formats.
+    pub ansi: Option<AnsiMode>,
+
+    /// The theme that maps rule kinds to styles.
+    pub theme: Theme,
+
+    /// Selectors choosing the rules that count as a match when filtering
+    /// lines. A rule is selected if its id or one of its tags equals a
+    /// selector, or its kind equals one or is a child of one (`level`
+    /// selects `level.error`). If empty, all rules are selected.
+    pub filter: Vec<String>,
+}
 
 /// A compiled highlighting engine.
 ///
-/// Internally, all rules are combined into a single regular expression
-/// using named capture groups to efficiently determine which rule
-/// produced a match.
+/// Internally, the rules of each layer are combined into a single regular
+/// expression using named capture groups to efficiently determine which
+/// rule produced a match.
+///
+/// Rules on layer `0` form the base layer, where the first rule to match
+/// claims the text. Rules on higher layers are overlays: they are matched
+/// independently over the whole line, and where they overlap text claimed
+/// by lower layers, the styles are composed.
+///
+/// Rules with `scope: line` are matched first and style the whole line
+/// beneath all layers.
+///
+/// Rules on the base layer can push and pop named contexts. While a
+/// context is on top of the stack, its rules replace the base layer.
 pub struct HighlightingEngine {
+    /// Compiled layers, ordered from the lowest to the highest.
+    layers: Vec<Layer>,
+    /// Rules styling whole lines, if any.
+    line_rules: Option<LineRules>,
+    /// Compiled contexts. The first one is `main`, the base layer of the
+    /// top-level rules.
+    contexts: Vec<CompiledContext>,
+    /// Context changes triggered by each rule.
+    transitions: Vec<Transition>,
+    /// Whether each rule is selected by the filter.
+    selected: Vec<bool>,
+    /// Resolved styles: one per rule, followed by capture styles.
+    styles: Vec<Style>,
+    /// What each of `styles` belongs to.
+    sources: Vec<StyleSource>,
+    /// The id of each rule.
+    rule_ids: Vec<Option<String>>,
+    /// The markup starting text in each of `styles`.
+    markup: Vec<String>,
+    /// Styled capture groups of each rule, as
+    /// `(capture index in the layer's regex, index into styles)`.
+    rule_captures: Vec<Vec<(usize, usize)>>,
+    /// End patterns of region rules, indexed by rule.
+    region_ends: Vec<Option<RegionEnd>>,
+    /// Offset of each rule's named group in its layer's regex.
+    rule_cap_idx: Vec<usize>,
+    formatter: Box<dyn Formatter>,
+    output: OutputFormat,
+    ansi: Option<AnsiMode>,
+}
+
+/// What a style of `HighlightingEngine::styles` belongs to.
+#[derive(Debug, Clone, Default)]
+struct StyleSource {
+    /// The rule, unless the style is a context's.
+    rule: Option<usize>,
+    /// The name or number of a styled capture group of the rule.
+    capture: Option<String>,
+    /// The context whose unmatched text has the style.
+    context: Option<String>,
+    kind: Option<String>,
+}
+
+/// A highlighted byte range of a line, as reported by
+/// [`HighlightingEngine::spans`].
+#[derive(Debug, Clone, Serialize)]
+pub struct HighlightSpan<'a> {
+    /// Byte offset of the start of the span in the line.
+    pub start: usize,
+    /// Byte offset of the end of the span in the line, exclusive.
+    pub end: usize,
+    /// Index of the matching rule: the top-level rules come first,
+    /// followed by the rules of each context, in the order of
+    /// [`RuleSet::iter`]. `None` for unmatched text inside a context
+    /// and for styles of the input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<usize>,
+    /// The id of the matching rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<&'a str>,
+    /// The kind of the rule, capture group or context.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'a str>,
+    /// The name or number of the capture group, for spans of styled
+    /// capture groups.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture: Option<&'a str>,
+    /// The context, for unmatched text inside a context.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<&'a str>,
+    /// The resolved style, including the theme's style for the kind.
+    pub style: &'a Style,
+}
+
+impl<'a> From<&'a StyledSpan> for HighlightSpan<'a> {
+    /// Reports a style that escape sequences of the input gave its text.
+    fn from(span: &'a StyledSpan) -> Self {
+        HighlightSpan {
+            start: span.start,
+            end: span.end,
+            rule: None,
+            id: None,
+            kind: None,
+            capture: None,
+            context: None,
+            style: &span.style,
+        }
+    }
+}
+
+/// The end pattern of a region rule.
+#[derive(Debug, Clone)]
+enum RegionEnd {
+    /// A pattern that is the same for every region.
+    Fixed(Regex),
+    /// A pattern referring to the begin match's capture groups with
+    /// `\1` … `\9`, compiled each time a region opens.
+    Template(String),
+}
+
+/// A region that was opened on a previous line and has not ended yet.
+#[derive(Debug, Clone)]
+struct OpenRegion {
+    rule: usize,
+    end: Regex,
+}
+
+/// Highlighting state carried from one line to the next, such as the
+/// regions left open at the end of a line.
+///
+/// Use one state for all lines of a stream, starting from
+/// `LineState::default()`.
+#[derive(Debug, Clone, Default)]
+pub struct LineState {
+    /// The state of each layer.
+    layers: Vec<LayerState>,
+    /// Whether a selected line rule matched the last line.
+    line_matched: bool,
+}
+
+impl LineState {
+    /// Returns `true` if a rule selected by the engine's filter matched
+    /// the last rendered line.
+    pub(crate) fn matched(&self) -> bool {
+        self.line_matched || self.layers.iter().any(|layer| layer.matched)
+    }
+}
+
+/// The state of a single layer.
+#[derive(Debug, Clone, Default)]
+struct LayerState {
+    /// The region left open by the previous line.
+    open: Option<OpenRegion>,
+    /// The context stack, as indices into `HighlightingEngine::contexts`.
+    /// Empty while the layer's own rules are active.
+    stack: Vec<usize>,
+    /// Whether a selected rule matched the last line.
+    matched: bool,
+}
+
+/// Contexts pushed beyond this depth are ignored, so that a rule pushing
+/// on every match cannot grow the stack without bound.
+const MAX_CONTEXT_DEPTH: usize = 64;
+
+/// A context compiled for matching.
+struct CompiledContext {
+    /// `None` if the context has no rules.
+    layer: Option<Layer>,
+    /// The style of unmatched text inside the context, as an index into
+    /// `HighlightingEngine::styles`.
+    style: Option<usize>,
+}
+
+/// The context changes a rule triggers when it matches.
+#[derive(Debug, Copy, Clone, Default)]
+struct Transition {
+    pop: bool,
+    push: Option<usize>,
+}
+
+/// The rules with `scope: line`, compiled into a set so that a line is
+/// scanned once for all of them.
+struct LineRules {
+    set: RegexSet,
+    /// The rule index of each pattern in the set.
+    rules: Vec<usize>,
+}
+
+/// The rules of one layer, compiled into a single alternation.
+#[derive(Clone)]
+struct Layer {
     regex: Regex,
-    cap_to_color: Vec<usize>,
-    ansi_colors: Vec<String>,
+    /// `(capture index, rule index)` of every rule's named group, in
+    /// capture order.
+    rule_groups: Vec<(usize, usize)>,
+}
+
+impl Layer {
+    /// Compiles the rules `indices` into one alternation and records the
+    /// capture index of each rule's named group in `rule_cap_idx`.
+    fn compile(indices: &[usize], patterns: &[String], rule_cap_idx: &mut [usize]) -> anyhow::Result<Self> {
+        let alternation: Vec<&str> = indices.iter().map(|&i| patterns[i].as_str()).collect();
+        let regex = build_line_regex(&alternation.join("|"))?;
+
+        let mut rule_groups = Vec::with_capacity(indices.len());
+        for (cap_idx, name) in regex.capture_names().enumerate() {
+            let Some(name) = name else { continue };
+            let Some(idx) = name.strip_prefix('r') else { continue };
+            let Ok(rule_idx) = idx.parse::<usize>() else { continue };
+
+            rule_groups.push((cap_idx, rule_idx));
+            rule_cap_idx[rule_idx] = cap_idx;
+        }
+
+        Ok(Layer { regex, rule_groups })
+    }
+}
+
+/// The name of the context formed by the top-level rules.
+const MAIN_CONTEXT: &str = "main";
+
+/// A styled byte range of a line.
+#[derive(Debug, Copy, Clone)]
+struct Span {
+    start: usize,
+    end: usize,
+    /// Index into `HighlightingEngine::styles`.
+    style: usize,
 }
 
 impl HighlightingEngine {
     /// Creates a new highlighting engine from a list of rules.
+    /// If `force_ignore_case` is `true`, all rules are compiled
     /// with case-insensitive matching, regardless of their individual
     /// `ignore_case` settings.
     ///
-    /// All rules are compiled into a single regular expression to minimize
-    /// matching overhead.
+    /// All rules of a layer are compiled into a single regular expression
+    /// to minimize matching overhead.
     ///
     /// # Errors
     ///
     /// Returns an error if the combined regular expression fails to compile.
     pub fn new(
-        rules: &[crate::rules::Rule],
+        rules: &[Rule],
         force_ignore_case: bool,
     ) -> anyhow::Result<Self> {
-        use regex::RegexBuilder;
+        let options = EngineOptions {
+            ignore_case: force_ignore_case,
+            ..Default::default()
+        };
+        Self::with_options(rules, &options)
+    }
+
+    /// Creates a new highlighting engine from a list of rules and the
+    /// given [`EngineOptions`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a regular expression fails to compile, a
+    /// rule's style cannot be resolved, or a rule styles a capture group
+    /// it does not have.
+    pub fn with_options(
+        rules: &[Rule],
+        options: &EngineOptions,
+    ) -> anyhow::Result<Self> {
+        Self::build(rules, &BTreeMap::new(), options)
+    }
+
+    /// Creates a new highlighting engine from a [`RuleSet`] with contexts
+    /// and the given [`EngineOptions`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for the same reasons as [`Self::with_options`],
+    /// or if a rule pushes an undefined context, a rule outside the base
+    /// layer pushes or pops a context, a line rule uses regions, contexts
+    /// or captures, two rules share an id, or a context is named `main`.
+    pub fn with_rule_set(
+        rule_set: &RuleSet,
+        options: &EngineOptions,
+    ) -> anyhow::Result<Self> {
+        Self::build(&rule_set.rules, &rule_set.contexts, options)
+    }
+
+    fn build(
+        main_rules: &[Rule],
+        contexts: &BTreeMap<String, Context>,
+        options: &EngineOptions,
+    ) -> anyhow::Result<Self> {
+        if contexts.contains_key(MAIN_CONTEXT) {
+            anyhow::bail!("Context name '{}' is reserved", MAIN_CONTEXT);
+        }
+
+        let mut ids = std::collections::HashSet::new();
+        for id in main_rules.iter().chain(contexts.values().flat_map(|c| &c.rules)).filter_map(|r| r.id.as_deref()) {
+            if !ids.insert(id) {
+                anyhow::bail!("Duplicate rule id '{}'", id);
+            }
+        }
+
+        // 所有规则统一编号：先是顶层规则，然后依次是各上下文中的规则
+        let rules: Vec<&Rule> = main_rules
+            .iter()
+            .chain(contexts.values().flat_map(|context| &context.rules))
+            .collect();
+
+        // 上下文样式，编号与 contexts 相同，main 没有样式
+        let context_styles: Vec<Option<Style>> = std::iter::once(None)
+            .chain(contexts.values().map(|context| {
+                Some(resolve_style(context.kind.as_deref(), context.effective_style(), &options.theme))
+            }))
+            .collect();
 
         let mut patterns = Vec::with_capacity(rules.len());
-        let mut ansi_colors = Vec::with_capacity(rules.len());
+        let mut styles = Vec::with_capacity(rules.len());
+        let mut sources = Vec::with_capacity(rules.len());
+        let mut layer_rules: BTreeMap<u8, Vec<usize>> = BTreeMap::new();
+        let mut context_rules: Vec<Vec<usize>> = vec![Vec::new(); contexts.len() + 1];
+        let mut transitions = Vec::with_capacity(rules.len());
+        let mut line_rules = Vec::new();
+
+        let context_index = |name: &str| -> Option<usize> {
+            if name == MAIN_CONTEXT {
+                return Some(0);
+            }
+            contexts.keys().position(|key| key == name).map(|idx| idx + 1)
+        };
 
         // 1. 构造每条规则的正则片段
-        for (i, rule) in rules.iter().enumerate() {
+        let owners = std::iter::repeat_n(0, main_rules.len()).chain(
+            contexts.values().enumerate().flat_map(|(idx, context)| std::iter::repeat_n(idx + 1, context.rules.len())),
+        );
+        for (i, (rule, owner)) in rules.iter().zip(owners).enumerate() {
             let base_pat = if rule.is_regex {
                 rule.keyword.clone()
             } else {
                 regex::escape(&rule.keyword)
             };
 
-            let effective_ignore_case = force_ignore_case || rule.ignore_case;
+            let effective_ignore_case = options.ignore_case || rule.ignore_case;
 
             let pat = if effective_ignore_case {
                 // 使用 inline flag，做到 per-rule ignore_case
@@ -50,33 +383,178 @@ impl HighlightingEngine {
 
             // 命名捕获组 r{i}
             patterns.push(format!("(?P<r{}>{})", i, pat));
-            ansi_colors.push(rule.color.to_ansi()?);
+
+            let style = resolve_style(rule.kind.as_deref(), rule.effective_style(), &options.theme);
+            styles.push(match &context_styles[owner] {
+                Some(context_style) => context_style.merge(&style),
+                None => style,
+            });
+            sources.push(StyleSource { rule: Some(i), kind: rule.kind.clone(), ..Default::default() });
+
+            if rule.layer != 0 && (owner != 0 || rule.push.is_some() || rule.pop) {
+                anyhow::bail!("Rule '{}' must be on layer 0 to use contexts", rule.label());
+            }
+            if rule.end.is_some() && (rule.push.is_some() || rule.pop) {
+                anyhow::bail!("Region rule '{}' cannot push or pop contexts", rule.label());
+            }
+            let push = match &rule.push {
+                Some(name) => Some(context_index(name).ok_or_else(|| {
+                    anyhow::anyhow!("Rule '{}' pushes undefined context '{}'", rule.label(), name)
+                })?),
+                None => None,
+            };
+            transitions.push(Transition { pop: rule.pop, push });
+
+            if rule.scope == Scope::Line {
+                if owner != 0 || rule.end.is_some() || push.is_some() || rule.pop || !rule.captures.is_empty() {
+                    anyhow::bail!(
+                        "Line rule '{}' cannot be part of a context or use end, push, pop or captures",
+                        rule.label()
+                    );
+                }
+                line_rules.push(i);
+            } else if owner == 0 {
+                layer_rules.entry(rule.layer).or_default().push(i);
+            } else {
+                context_rules[owner].push(i);
+            }
+        }
+
+        // 2. 每一层（以及每个上下文）的规则编译为一个合并后的正则
+        //
+        // 同时记录每条规则的命名捕获组在所在正则中的位置
+        let mut rule_cap_idx = vec![0; rules.len()];
+
+        let layers = layer_rules
+            .values()
+            .map(|indices| Layer::compile(indices, &patterns, &mut rule_cap_idx))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // main 上下文即顶层规则的第 0 层
+        let main_layer = layer_rules.contains_key(&0).then(|| layers[0].clone());
+        let mut compiled_contexts = vec![CompiledContext { layer: main_layer, style: None }];
+        for indices in &context_rules[1..] {
+            let layer = if indices.is_empty() {
+                None
+            } else {
+                Some(Layer::compile(indices, &patterns, &mut rule_cap_idx)?)
+            };
+            compiled_contexts.push(CompiledContext { layer, style: None });
         }
 
-        // 2. 编译合并后的正则
-        let regex = RegexBuilder::new(&patterns.join("|"))
-            .multi_line(true)
-            .dot_matches_new_line(false)
-            .build()?;
+        // 整行规则编译为一个 RegexSet
+        let line_rules = if line_rules.is_empty() {
+            None
+        } else {
+            let set = regex::RegexSetBuilder::new(line_rules.iter().map(|&i| &patterns[i]))
+                .multi_line(true)
+                .build()?;
+            Some(LineRules { set, rules: line_rules })
+        };
 
-        // 3. 建立 capture_index -> rule_index 的 O(1) 映射表
+        // 3. 解析每条规则中需要单独着色的捕获组
         //
-        // cap_to_color[cap_idx] = rule_idx
-        // 未使用的 capture index 用 usize::MAX 标记
-        let mut cap_to_color = vec![usize::MAX; regex.captures_len()];
+        // 规则自身的第 k 个捕获组在所在层的正则中位于 r{i} 之后第 k 个位置
+        let mut rule_captures = vec![Vec::new(); rules.len()];
+        // 单独编译的规则，用于获得其捕获组的数量和名称
+        let mut standalone: Vec<Option<Regex>> = vec![None; rules.len()];
 
-        for (cap_idx, name) in regex.capture_names().enumerate() {
-            let Some(name) = name else { continue };
-            let Some(idx) = name.strip_prefix('r') else { continue };
-            let Ok(rule_idx) = idx.parse::<usize>() else { continue };
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.captures.is_empty() {
+                continue;
+            }
+            let standalone = standalone[i].insert(Regex::new(&patterns[i])?);
+
+            for (group, capture) in &rule.captures {
+                let local_idx = match group.parse::<usize>() {
+                    Ok(idx) if idx >= 1 && idx + 1 < standalone.captures_len() => idx,
+                    Ok(_) => anyhow::bail!("Rule '{}' has no capture group {}", rule.label(), group),
+                    Err(_) => standalone
+                        .capture_names()
+                        .position(|name| name == Some(group.as_str()))
+                        .map(|idx| idx - 1)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("Rule '{}' has no capture group named '{}'", rule.label(), group)
+                        })?,
+                };
+
+                let style = styles[i].merge(&resolve_style(
+                    capture.kind.as_deref(),
+                    capture.effective_style(),
+                    &options.theme,
+                ));
+                styles.push(style);
+                sources.push(StyleSource {
+                    rule: Some(i),
+                    capture: Some(group.clone()),
+                    kind: capture.kind.clone().or_else(|| rule.kind.clone()),
+                    context: None,
+                });
+                rule_captures[i].push((rule_cap_idx[i] + local_idx, styles.len() - 1));
+            }
+        }
+
+        // 4. 编译区域规则的结束模式，并检查反向引用的捕获组是否存在
+        let mut region_ends = Vec::with_capacity(rules.len());
+        for (i, rule) in rules.iter().enumerate() {
+            let end = rule
+                .end
+                .as_deref()
+                .map(|end| RegionEnd::compile(end, rule.is_regex, options.ignore_case || rule.ignore_case))
+                .transpose()?;
+            if let Some(group) = end.as_ref().and_then(RegionEnd::highest_backref) {
+                if standalone[i].is_none() {
+                    standalone[i] = Some(Regex::new(&patterns[i])?);
+                }
+                // 组 0 和外层的命名组 r{i} 不属于规则自身
+                if group + 1 >= standalone[i].as_ref().unwrap().captures_len() {
+                    anyhow::bail!("Rule '{}' has no capture group {}", rule.label(), group);
+                }
+            }
+            region_ends.push(end);
+        }
 
-            cap_to_color[cap_idx] = rule_idx;
+        // 5. 过滤行时计入匹配的规则
+        let selected = rules
+            .iter()
+            .map(|rule| {
+                options.filter.is_empty() || options.filter.iter().any(|f| rule.is_selected_by(f))
+            })
+            .collect();
+
+        // 6. 上下文自身的样式，main 没有样式
+        let named_contexts = compiled_contexts.iter_mut().zip(context_styles).skip(1).zip(contexts);
+        for ((context, style), (name, source)) in named_contexts {
+            if let Some(style) = style {
+                styles.push(style);
+                sources.push(StyleSource { context: Some(name.clone()), kind: source.kind.clone(), ..Default::default() });
+                context.style = Some(styles.len() - 1);
+            }
         }
 
+        let default = options.theme.style_for("default");
+        let formatter = crate::format::formatter(&options.output, options.color_depth, default, &styles)?;
+        let markup = styles
+            .iter()
+            .map(|style| formatter.open(style))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
         Ok(Self {
-            regex,
-            cap_to_color,
-            ansi_colors,
+            layers,
+            line_rules,
+            contexts: compiled_contexts,
+            transitions,
+            selected,
+            styles,
+            sources,
+            rule_ids: rules.iter().map(|rule| rule.id.clone()).collect(),
+            markup,
+            rule_captures,
+            region_ends,
+            rule_cap_idx,
+            formatter,
+            output: options.output.clone(),
+            ansi: options.ansi,
         })
     }
 
@@ -90,9 +568,10 @@ impl HighlightingEngine {
     /// # use highlite::rules::{Rule, Color};
     /// let rules = vec![Rule {
     ///     keyword: "Ok".into(),
-    ///     color: Color::Preset{ name: "Green".into() },
+    ///     color: Some(Color::Preset{ name: "Green".into() }),
     ///     is_regex: false,
     ///     ignore_case: true,
+    ///     ..Default::default()
     /// }];
     ///
     /// let engine = HighlightingEngine::new(&rules, false).unwrap();
@@ -106,49 +585,523 @@ impl HighlightingEngine {
     /// # use highlite::rules::{Rule, Color};
     /// let rules = vec![Rule {
     ///     keyword: "Ok".into(),
-    ///     color: Color::Preset{ name: "Green".into() },
+    ///     color: Some(Color::Preset{ name: "Green".into() }),
     ///     is_regex: false,
     ///     ignore_case: false,
+    ///     ..Default::default()
     /// }];
     ///
     /// let engine = HighlightingEngine::new(&rules, false).unwrap();
     /// let out = engine.highlight_line("Status: OK\n");
     /// assert!(!out.contains("\x1b[32mOK\x1b[0m"));
     /// ```
+    ///
+    /// Each call starts from a fresh state, so regions opened and contexts
+    /// pushed on earlier lines are not continued.
     pub fn highlight_line(&self, input: &str) -> String {
         let mut out = String::with_capacity(input.len() + 16);
-        self.render_line(input, &mut out);
+        self.render_line(input, &mut LineState::default(), &mut out);
         out
     }
 
+    /// Returns `true` if this engine emits no escape sequences at all,
+    /// so input can be passed through without matching.
+    pub(crate) fn is_plain(&self) -> bool {
+        self.formatter.is_plain()
+    }
+
+    /// Returns the markup preceding all lines of the output, such as the
+    /// start of an HTML document. Empty for ANSI output.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a color cannot be converted for the output
+    /// format.
+    pub fn document_start(&self) -> anyhow::Result<String> {
+        self.formatter.header()
+    }
+
+    /// Returns the markup following all lines of the output. Empty for
+    /// ANSI output.
+    pub fn document_end(&self) -> String {
+        self.formatter.footer()
+    }
+
+    /// Returns `true` if the output format needs all rendered lines before
+    /// anything can be written, such as SVG, which is sized to the text.
+    /// The lines are then passed to [`HighlightingEngine::document`].
+    pub fn buffers_output(&self) -> bool {
+        self.formatter.buffered()
+    }
+
+    /// Turns all rendered lines into the output of a format that
+    /// [buffers its output](HighlightingEngine::buffers_output). Returns
+    /// `body` unchanged for other formats.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a color cannot be converted for the format.
+    pub fn document(&self, body: &str) -> anyhow::Result<String> {
+        self.formatter.document(body)
+    }
+
     /// Renders a single line of input with highlighting applied.
     ///
-    /// Matched segments are wrapped in ANSI color escape sequences.
-    /// The output buffer is cleared before writing.
-    pub(crate) fn render_line(&self, input: &str, output: &mut String) {
+    /// Matched segments are wrapped in the markup of their rule's style,
+    /// such as an ANSI escape sequence, and all text is escaped as the
+    /// output format requires. Rules with an empty style leave the text
+    /// untouched. The output buffer is cleared before writing.
+    ///
+    /// `state` carries open regions and the context stack between
+    /// consecutive lines of the same stream.
+    pub(crate) fn render_line(&self, input: &str, state: &mut LineState, output: &mut String) {
+        output.clear();
+        let line_style = self.begin_line(input, state);
+        match (self.layers.as_slice(), line_style) {
+            ([], None) => self.formatter.push_text(output, input),
+            // 只有一层时无需合成样式，直接输出
+            ([layer], None) => {
+                let mut last = 0;
+                let mut pending: Option<Span> = None;
+                let mut flush = |output: &mut String, span: Span| {
+                    self.formatter.push_text(output, &input[last..span.start]);
+                    self.push_styled(output, &self.markup[span.style], &input[span.start..span.end]);
+                    last = span.end;
+                };
+                self.layer_spans(layer, input, &mut state.layers[0], |span| {
+                    // 合并相邻且样式相同的 span，减少转义序列
+                    if let Some(prev) = pending.as_mut()
+                        && prev.end == span.start
+                        && self.markup[prev.style] == self.markup[span.style]
+                    {
+                        prev.end = span.end;
+                        return;
+                    }
+                    if let Some(prev) = pending.replace(span) {
+                        flush(output, prev);
+                    }
+                });
+                if let Some(prev) = pending {
+                    flush(output, prev);
+                }
+                // 写入剩余文本
+                self.formatter.push_text(output, &input[last..]);
+            }
+            (layers, line_style) => self.render_layered(layers, line_style, &[], input, state, output),
+        }
+    }
+
+    /// Renders a line whose escape sequences were turned into styles by an
+    /// [`AnsiParser`](crate::ansi::AnsiParser).
+    ///
+    /// The styles of the input lie beneath all rules, so rules override
+    /// their colors but keep their attributes. Otherwise the same as
+    /// [`HighlightingEngine::render_line`] on the visible text.
+    pub(crate) fn render_styled_line(&self, line: &StyledLine, state: &mut LineState, output: &mut String) {
         output.clear();
-        let mut last_match = 0;
+        let line_style = self.begin_line(&line.text, state);
+        self.render_layered(&self.layers, line_style, &line.spans, &line.text, state, output);
+    }
+
+    /// Reports the highlighted spans of a line, without rendering it.
+    ///
+    /// The span of a matching `scope: line` rule comes first, followed by
+    /// the spans of each layer from the lowest to the highest. Spans of
+    /// one layer are ordered and never overlap, but spans of different
+    /// layers can. Spans never include the line ending.
+    ///
+    /// `state` carries open regions and the context stack between
+    /// consecutive lines of the same stream; use a fresh
+    /// [`LineState`] for the first line.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use highlite::highlight::{EngineOptions, HighlightingEngine, LineState};
+    /// use highlite::rules::{Rule, RuleSet};
+    ///
+    /// let rules = vec![Rule { id: Some("error".into()), keyword: "error".into(), ..Default::default() }];
+    /// let engine = HighlightingEngine::with_rule_set(&RuleSet::from(rules), &EngineOptions::default()).unwrap();
+    ///
+    /// let mut state = LineState::default();
+    /// let spans: Vec<_> = engine.spans("an error\n", &mut state).collect();
+    /// assert_eq!((spans[0].start, spans[0].end, spans[0].id), (3, 8, Some("error")));
+    /// ```
+    pub fn spans<'a>(&'a self, input: &str, state: &mut LineState) -> impl Iterator<Item = HighlightSpan<'a>> + use<'a> {
+        let line_style = self.begin_line(input, state);
+        let line_end = input.trim_end_matches(['\n', '\r']).len();
+
+        let mut spans: Vec<Span> = line_style.map(|style| Span { start: 0, end: line_end, style }).into_iter().collect();
+        for (layer, layer_state) in self.layers.iter().zip(state.layers.iter_mut()) {
+            self.layer_spans(layer, input, layer_state, |span| spans.push(span));
+        }
 
-        for caps in self.regex.captures_iter(input) {
+        spans.into_iter().map(move |span| {
+            let source = &self.sources[span.style];
+            HighlightSpan {
+                start: span.start,
+                end: span.end,
+                rule: source.rule,
+                id: source.rule.and_then(|rule| self.rule_ids[rule].as_deref()),
+                kind: source.kind.as_deref(),
+                capture: source.capture.as_deref(),
+                context: source.context.as_deref(),
+                style: &self.styles[span.style],
+            }
+        })
+    }
+
+    /// Returns the format this engine renders lines in.
+    pub fn output_format(&self) -> &OutputFormat {
+        &self.output
+    }
+
+    /// Returns how escape sequences in the input are handled, or `None`
+    /// if they are passed through as text.
+    pub fn ansi_mode(&self) -> Option<AnsiMode> {
+        self.ansi.or(self.output.interprets_escapes().then_some(AnsiMode::Preserve))
+    }
+
+    /// Prepares `state` for a new line and matches the line rules.
+    /// Returns the style of the first matching line rule.
+    fn begin_line(&self, input: &str, state: &mut LineState) -> Option<usize> {
+        state.layers.resize_with(self.layers.len(), Default::default);
+        for layer in &mut state.layers {
+            layer.matched = false;
+        }
+        let (line_style, line_matched) = self.line_style(input);
+        state.line_matched = line_matched;
+        line_style
+    }
+
+    /// Returns the style of the first line rule matching `input`, as an
+    /// index into `styles`, and whether a selected line rule matched.
+    fn line_style(&self, input: &str) -> (Option<usize>, bool) {
+        let Some(line_rules) = &self.line_rules else {
+            return (None, false);
+        };
+        let mut matches = line_rules.set.matches(input).into_iter().map(|i| line_rules.rules[i]);
+        let first = matches.next();
+        let matched = first.into_iter().chain(matches).any(|rule| self.selected[rule]);
+        (first, matched)
+    }
+
+    /// Renders a line matched against several layers, composing the
+    /// styles of overlapping spans from the lowest layer upwards.
+    ///
+    /// `line_style` is the style of a matching line rule, which lies
+    /// beneath all layers. `base` are the styles of the input itself,
+    /// beneath everything else.
+    fn render_layered(
+        &self,
+        layers: &[Layer],
+        line_style: Option<usize>,
+        base: &[StyledSpan],
+        input: &str,
+        state: &mut LineState,
+        output: &mut String,
+    ) {
+        // 整行样式作为最底层，同样不包含行尾的换行符
+        let line_end = input.trim_end_matches(['\n', '\r']).len();
+        let line_span = line_style.map(|style| vec![Span { start: 0, end: line_end, style }]);
+
+        let spans: Vec<Vec<Span>> = line_span
+            .into_iter()
+            .chain(layers.iter().zip(state.layers.iter_mut()).map(|(layer, layer_state)| {
+                let mut spans = Vec::new();
+                self.layer_spans(layer, input, layer_state, |span| spans.push(span));
+                spans
+            }))
+            .collect();
+
+        // 所有 span 的边界将该行切分为若干段，每段内覆盖它的 span 不变
+        let mut bounds: Vec<usize> = spans
+            .iter()
+            .flatten()
+            .flat_map(|span| [span.start, span.end])
+            .chain(base.iter().flat_map(|span| [span.start, span.end]))
+            .chain([0, input.len()])
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut cursors = vec![0; spans.len()];
+        let mut base_cursor = 0;
+        let mut covering = Vec::with_capacity(spans.len());
+        let mut pending_markup = Cow::Borrowed("");
+        let mut pending_start = 0;
+
+        // 每段以其起点标识，最后一个边界即行尾
+        for &start in &bounds[..bounds.len() - 1] {
+            covering.clear();
+            for (layer_spans, cursor) in spans.iter().zip(cursors.iter_mut()) {
+                while *cursor < layer_spans.len() && layer_spans[*cursor].end <= start {
+                    *cursor += 1;
+                }
+                if let Some(span) = layer_spans.get(*cursor)
+                    && span.start <= start
+                {
+                    covering.push(span.style);
+                }
+            }
+
+            while base_cursor < base.len() && base[base_cursor].end <= start {
+                base_cursor += 1;
+            }
+            let base_style = base.get(base_cursor).filter(|span| span.start <= start).map(|span| &span.style);
+
+            let markup = match (base_style, covering.as_slice()) {
+                (None, []) => Cow::Borrowed(""),
+                (None, [style]) => Cow::Borrowed(self.markup[*style].as_str()),
+                (base_style, covering) => {
+                    let composed = covering
+                        .iter()
+                        .fold(base_style.cloned().unwrap_or_default(), |acc, style| acc.merge(&self.styles[*style]));
+                    // 各颜色在构造时已验证过，合成后的样式不会出错
+                    Cow::Owned(self.formatter.open(&composed).unwrap_or_default())
+                }
+            };
+
+            // 合并相邻且样式相同的段，减少转义序列
+            if markup != pending_markup {
+                self.push_styled(output, &pending_markup, &input[pending_start..start]);
+                pending_markup = markup;
+                pending_start = start;
+            }
+        }
+        self.push_styled(output, &pending_markup, &input[pending_start..]);
+    }
+
+    /// Matches a layer against a line and reports the resulting spans in
+    /// order. The spans of one layer never overlap.
+    ///
+    /// For rules with styled capture groups, the match is split into
+    /// spans for the styled groups and spans in the rule's own style.
+    ///
+    /// `state` holds the layer's open region and context stack left by the
+    /// previous line. An open region is continued first; while a context
+    /// is on the stack, its rules are matched instead of the layer's, and
+    /// unmatched text gets the context's style. Both are updated for the
+    /// next line.
+    fn layer_spans(
+        &self,
+        layer: &Layer,
+        input: &str,
+        state: &mut LayerState,
+        mut emit: impl FnMut(Span),
+    ) {
+        // 区域和上下文不包含行尾的换行符，使重置序列出现在换行之前
+        let line_end = input.trim_end_matches(['\n', '\r']).len();
+        let mut pos = 0;
+
+        // 输出当前上下文中未被规则匹配的文本
+        let emit_gap = |stack: &[usize], start: usize, end: usize, emit: &mut dyn FnMut(Span)| {
+            if let Some(style) = stack.last().and_then(|&ctx| self.contexts[ctx].style)
+                && start < end
+            {
+                emit(Span { start, end, style });
+            }
+        };
+
+        if let Some(region) = state.open.take() {
+            state.matched |= self.selected[region.rule];
+            match region.end.find(input) {
+                Some(m) => {
+                    emit(Span { start: 0, end: m.end(), style: region.rule });
+                    pos = m.end();
+                }
+                None => {
+                    emit(Span { start: 0, end: line_end, style: region.rule });
+                    state.open = Some(region);
+                    return;
+                }
+            }
+        }
+        let mut gap = pos;
+
+        while pos <= input.len() {
+            let active = match state.stack.last() {
+                Some(&ctx) => self.contexts[ctx].layer.as_ref(),
+                None => Some(layer),
+            };
+            let Some(caps) = active.and_then(|active| active.regex.captures_at(input, pos)) else {
+                break;
+            };
+            let active = active.unwrap();
             let m = caps.get(0).unwrap();
 
-            output.push_str(&input[last_match..m.start()]);
+            // 空匹配时前进一个字符，避免死循环
+            pos = if m.is_empty() {
+                input[m.end()..].chars().next().map_or(input.len() + 1, |c| m.end() + c.len_utf8())
+            } else {
+                m.end()
+            };
+
+            let Some(&(_, rule_idx)) = active
+                .rule_groups
+                .iter()
+                .find(|(cap_idx, _)| caps.get(*cap_idx).is_some())
+            else {
+                continue;
+            };
+
+            emit_gap(&state.stack, gap, m.start(), &mut emit);
+            gap = gap.max(m.end());
+            state.matched |= self.selected[rule_idx];
 
-            for (cap_idx, color_idx) in self.cap_to_color.iter().enumerate() {
-                if *color_idx == usize::MAX {
+            if let Some(region_end) = &self.region_ends[rule_idx] {
+                let Some(end) = region_end.resolve(&caps, self.rule_cap_idx[rule_idx]) else {
                     continue;
+                };
+                match end.find_at(input, m.end()) {
+                    Some(e) => {
+                        emit(Span { start: m.start(), end: e.end(), style: rule_idx });
+                        pos = pos.max(e.end());
+                        gap = gap.max(e.end());
+                    }
+                    None => {
+                        emit(Span { start: m.start(), end: line_end.max(m.end()), style: rule_idx });
+                        state.open = Some(OpenRegion { rule: rule_idx, end });
+                        return;
+                    }
                 }
-                if let Some(sub) = caps.get(cap_idx) {
-                    output.push_str(&self.ansi_colors[*color_idx]);
-                    output.push_str(sub.as_str());
-                    output.push_str("\x1b[0m");
-                    break;
-                }
+                continue;
             }
 
-            last_match = m.end();
+            if self.rule_captures[rule_idx].is_empty() {
+                emit(Span { start: m.start(), end: m.end(), style: rule_idx });
+            } else {
+                self.emit_captures(&caps, rule_idx, &mut emit);
+            }
+
+            // 上下文切换
+            let transition = self.transitions[rule_idx];
+            if transition.pop {
+                state.stack.pop();
+            }
+            if let Some(ctx) = transition.push
+                && state.stack.len() < MAX_CONTEXT_DEPTH
+            {
+                state.stack.push(ctx);
+            }
         }
-        // 写入剩余文本
-        output.push_str(&input[last_match..]);
+
+        emit_gap(&state.stack, gap, line_end, &mut emit);
     }
-}
\ No newline at end of file
+
+    /// Appends `text` wrapped in the markup `start` and the formatter's
+    /// closing markup. Empty text is skipped, and empty markup leaves the
+    /// text as is.
+    fn push_styled(&self, output: &mut String, start: &str, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if start.is_empty() {
+            self.formatter.push_text(output, text);
+        } else {
+            output.push_str(start);
+            self.formatter.push_text(output, text);
+            output.push_str(self.formatter.close());
+        }
+    }
+
+    /// Reports the spans of a match of a rule with styled capture groups:
+    /// spans for the styled groups and spans in the rule's own style.
+    fn emit_captures(&self, caps: &Captures, rule_idx: usize, emit: &mut impl FnMut(Span)) {
+        let m = caps.get(0).unwrap();
+        let mut groups: Vec<Span> = self.rule_captures[rule_idx]
+            .iter()
+            .filter_map(|&(cap_idx, style)| {
+                caps.get(cap_idx).map(|g| Span { start: g.start(), end: g.end(), style })
+            })
+            .collect();
+        // 按起始位置排序，起点相同时外层（更长的）捕获组优先
+        groups.sort_by_key(|g| (g.start, std::cmp::Reverse(g.end)));
+
+        let mut pos = m.start();
+        for group in groups {
+            // 跳过与已输出部分重叠的捕获组
+            if group.start < pos || group.start == group.end {
+                continue;
+            }
+            if pos < group.start {
+                emit(Span { start: pos, end: group.start, style: rule_idx });
+            }
+            emit(group);
+            pos = group.end;
+        }
+        if pos < m.end() {
+            emit(Span { start: pos, end: m.end(), style: rule_idx });
+        }
+    }
+}
+
+impl RegionEnd {
+    /// Compiles the end pattern of a region rule.
+    fn compile(end: &str, is_regex: bool, ignore_case: bool) -> anyhow::Result<Self> {
+        let pattern = if is_regex { end.to_string() } else { regex::escape(end) };
+        let pattern = if ignore_case { format!("(?i:{})", pattern) } else { pattern };
+
+        if is_regex && backrefs(&pattern).next().is_some() {
+            // 先用空字符串代替反向引用，提前发现语法错误
+            Regex::new(&replace_backrefs(&pattern, |_| String::new()))?;
+            Ok(RegionEnd::Template(pattern))
+        } else {
+            Ok(RegionEnd::Fixed(build_line_regex(&pattern)?))
+        }
+    }
+
+    /// Returns the highest capture group the end pattern refers to, if it
+    /// has backreferences.
+    fn highest_backref(&self) -> Option<usize> {
+        match self {
+            RegionEnd::Fixed(_) => None,
+            RegionEnd::Template(template) => backrefs(template).max(),
+        }
+    }
+
+    /// Returns the end pattern for a region opened by `caps`, whose rule's
+    /// own groups start after capture index `base`.
+    fn resolve(&self, caps: &Captures, base: usize) -> Option<Regex> {
+        match self {
+            RegionEnd::Fixed(regex) => Some(regex.clone()),
+            RegionEnd::Template(template) => {
+                let pattern = replace_backrefs(template, |group| {
+                    regex::escape(caps.get(base + group).map_or("", |g| g.as_str()))
+                });
+                build_line_regex(&pattern).ok()
+            }
+        }
+    }
+}
+
+/// Matches a backreference (`\1` … `\9`) in a region's end pattern, or an
+/// escaped backslash, so that `\\1` is not taken for a backreference.
+static BACKREF_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\\(?:\\|([1-9]))").unwrap());
+
+/// Returns the groups the backreferences of `pattern` refer to.
+fn backrefs(pattern: &str) -> impl Iterator<Item = usize> + use<'_> {
+    BACKREF_RE.captures_iter(pattern).filter_map(|c| Some(c.get(1)?.as_str().parse().unwrap()))
+}
+
+/// Replaces the backreferences of `pattern` with `replace(group)`,
+/// leaving escaped backslashes as they are.
+fn replace_backrefs(pattern: &str, mut replace: impl FnMut(usize) -> String) -> std::borrow::Cow<'_, str> {
+    BACKREF_RE.replace_all(pattern, |c: &Captures| match c.get(1) {
+        Some(group) => replace(group.as_str().parse().unwrap()),
+        None => c[0].to_string(),
+    })
+}
+
+/// Compiles a pattern with the same flags as the layer regexes.
+fn build_line_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    regex::RegexBuilder::new(pattern).multi_line(true).build()
+}
+
+/// Layers a rule's explicit style on top of the theme's style for `kind`.
+fn resolve_style(kind: Option<&str>, explicit: Style, theme: &Theme) -> Style {
+    match kind.and_then(|k| theme.style_for(k)) {
+        Some(theme_style) => theme_style.merge(&explicit),
+        None => explicit,
+    }
+}
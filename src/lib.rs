@@ -10,7 +10,7 @@
 //! - Per-rule and global case-insensitive matching
 //! - Support for preset ANSI colors and 24-bit RGB colors
 //! - Read from files or `stdin`
-//! - Efficient multi-pattern matching using a single compiled regex
+//! - Efficient multi-pattern matching using one compiled regex per layer
 //!
 //! ## Example
 //!
@@ -20,6 +20,7 @@
 //! cat file.txt | highlite --config rules.yaml
 //! ```
 
+use std::collections::VecDeque;
 use std::io::{BufRead, Write};
 
 /// Types related to highlighting rules and color definitions.
@@ -38,8 +39,9 @@ pub mod arg_parser;
 
 /// High-performance text highlighting engine.
 ///
-/// This module compiles all rules into a single regular expression and
-/// efficiently renders matched text with ANSI color sequences.
+/// This module combines the rules of each layer and context into a single
+/// regular expression and efficiently renders matched text with ANSI color
+/// sequences.
 ///
 /// # Examples
 ///
@@ -49,9 +51,10 @@ pub mod arg_parser;
 /// let rules = vec![
 ///     Rule {
 ///         keyword: "error".into(),
-///         color: Color::Preset{ name: "Red".into() },
+///         color: Some(Color::Preset{ name: "Red".into() }),
 ///         is_regex: false,
 ///         ignore_case: false,
+///         ..Default::default()
 ///     },
 /// ];
 ///
@@ -62,6 +65,36 @@ pub mod arg_parser;
 /// assert!(out.contains("\x1b[31m"));
 /// ```
 pub mod highlight;
+
+/// Output formats of the highlighting engine.
+///
+/// This module turns styled text into ANSI escape sequences, HTML or SVG,
+/// so that the same rules can be rendered for terminals and documents.
+pub mod format;
+
+/// Parsing of ANSI escape sequences.
+///
+/// This module interprets the SGR sequences of already colored text as a
+/// terminal does, turning it back into text and styles.
+pub mod ansi;
+
+/// Terminal capability detection.
+///
+/// This module determines how many colors the output terminal supports,
+/// so that styles can be reduced to escape sequences it understands.
+pub mod term;
+
+/// Themes mapping semantic rule kinds to styles.
+///
+/// Rules can name a `kind` such as `comment` or `level.error` instead of
+/// hardcoding colors; the selected theme decides how each kind is rendered.
+pub mod theme;
+
+/// Preset detection from the input.
+///
+/// This module picks a preset for an input from its file name, its `#!`
+/// line or its first lines, when no configuration or preset is given.
+pub mod detect;
 mod preset;
 
 /// Executes the main program logic using the provided CLI configuration.
@@ -70,6 +103,12 @@ mod preset;
 /// initializes the highlighting engine, and processes either the specified
 /// input file or standard input.
 ///
+/// Without `--config` or `--preset`, a default configuration file is looked
+/// up with [`arg_parser::discover_config`] unless `--no-config` is given.
+/// If it defines no rules, a preset is picked with
+/// [`detect::detect_preset`], using the file's `detect` mappings, and
+/// falling back to the `logs` preset.
+///
 /// If no input file is provided, the function reads from `stdin`.
 /// When `stdin` is connected to a terminal, an informational message is printed
 /// to stderr before waiting for input.
@@ -92,15 +131,38 @@ mod preset;
 /// # Examples
 ///
 /// ```no_run
-/// use highlite::{run, arg_parser::CliArgs};
+/// use highlite::{run, arg_parser::{CliArgs, ColorChoice, ColorDepthArg, OutputFormatArg}};
 ///
 /// let cli_args = CliArgs {
 ///     ignore_case: false,
 ///     file: Some(String::from("path/to/file").into()),
 ///     config: Some(String::from("path/to/config.yaml").into()),
+///     no_config: false,
 ///     follow_file: None,
 ///     follow_journal: false,
 ///     preset: None,
+///     preset_path: Vec::new(),
+///     color_depth: ColorDepthArg::Auto,
+///     color: ColorChoice::Auto,
+///     theme: "dark".into(),
+///     enable_tag: Vec::new(),
+///     disable_tag: Vec::new(),
+///     disable_rule: Vec::new(),
+///     only_matching_lines: false,
+///     filter: Vec::new(),
+///     invert: false,
+///     after_context: None,
+///     before_context: None,
+///     context: None,
+///     output: OutputFormatArg::Ansi,
+///     html_classes: false,
+///     html_standalone: false,
+///     svg_font: None,
+///     svg_font_size: None,
+///     svg_background: None,
+///     svg_width: None,
+///     ansi: None,
+///     command: None,
 /// };
 ///
 /// run(cli_args).unwrap();
@@ -109,59 +171,374 @@ mod preset;
 /// This function flushes all output before returning.
 ///
 pub fn run(cli_args: arg_parser::CliArgs) -> anyhow::Result<()> {
-    use std::fs;
-    use std::io::{BufReader, BufWriter, IsTerminal, Write};
-    use std::process::{Command, Stdio};
+    use std::io::BufWriter;
 
+    // 用户预设目录：--preset-path 优先，其次是 XDG 配置目录
+    let search_dirs: Vec<_> = cli_args
+        .preset_path
+        .iter()
+        .cloned()
+        .chain(arg_parser::config_home().map(|dir| dir.join("presets")))
+        .collect();
 
-    let raw_rules = if let Some(config_path) = cli_args.config.as_ref() {
-        arg_parser::load_rules_from_file(config_path)?
+    match &cli_args.command {
+        Some(arg_parser::Command::ListPresets) => {
+            let mut writer = BufWriter::new(std::io::stdout().lock());
+            write_preset_list(&preset::list_presets(&search_dirs), &mut writer)?;
+            return Ok(writer.flush()?);
+        }
+        Some(arg_parser::Command::DumpPreset { name }) => {
+            let rule_set = preset::find_preset(name, &search_dirs)?;
+            let mut writer = BufWriter::new(std::io::stdout().lock());
+            if let Some(description) = preset::builtin_description(name) {
+                writeln!(writer, "# {}: {}", name, description)?;
+            }
+            writer.write_all(serde_yml::to_string(&rule_set)?.as_bytes())?;
+            return Ok(writer.flush()?);
+        }
+        None => {}
+    }
+
+    // 输入在配置加载之后才打开，只有自动检测预设时才需要提前读取开头几行
+    let mut input: Option<Box<dyn BufRead>> = None;
+    let mut rule_set = if let Some(config_path) = cli_args.config.as_ref() {
+        arg_parser::load_rule_set_from_file(config_path)?
     } else if let Some(preset_name) = cli_args.preset.as_ref() {
-        preset::get_preset(preset_name)?
+        preset::find_preset(preset_name, &search_dirs)?
     } else {
-        // 默认预设
-        preset::get_preset("logs")?
+        let config = match (!cli_args.no_config).then(arg_parser::discover_config).flatten() {
+            Some(config_path) => arg_parser::load_config(&config_path)
+                .map_err(|e| anyhow::anyhow!("{} (in {})", e, config_path.display()))?,
+            None => arg_parser::Config::default(),
+        };
+        if !config.rule_set.rules.is_empty() {
+            config.rule_set
+        } else {
+            // journalctl 的输出总是日志，无需检测
+            let detected = if cli_args.follow_journal {
+                None
+            } else {
+                let path = cli_args.follow_file.as_deref().or(cli_args.file.as_deref());
+                let input = input.insert(open_input(&cli_args)?);
+                detect::detect_preset(path, input.fill_buf()?, &config.detect)
+            };
+            preset::find_preset(detected.as_deref().unwrap_or("logs"), &search_dirs)?
+        }
     };
 
-    let engine = highlight::HighlightingEngine::new(&raw_rules, cli_args.ignore_case)?;
+    select_rules(&mut rule_set, &cli_args)?;
+
+    // 输出被重定向或设置了 NO_COLOR 时不输出任何转义序列
+    let color_depth = cli_args.color.resolve_depth(cli_args.color_depth.resolve());
+    let options = highlight::EngineOptions {
+        ignore_case: cli_args.ignore_case,
+        color_depth,
+        theme: theme::Theme::load(&cli_args.theme)?,
+        filter: cli_args.filter.clone(),
+        output: cli_args.output_format(),
+        ansi: cli_args.ansi.map(arg_parser::AnsiModeArg::resolve),
+    };
+    let line_filter = (cli_args.only_matching_lines || cli_args.invert || !cli_args.filter.is_empty()).then(|| {
+        LineFilter {
+            invert: cli_args.invert,
+            before: cli_args.before_context.or(cli_args.context).unwrap_or(0),
+            after: cli_args.after_context.or(cli_args.context).unwrap_or(0),
+        }
+    });
+    let engine = highlight::HighlightingEngine::with_rule_set(&rule_set, &options)?;
+    let input = match input {
+        Some(input) => input,
+        None => open_input(&cli_args)?,
+    };
     let stdout = std::io::stdout();
     let mut writer = BufWriter::new(stdout.lock());
 
-    // 如果是跟随日志选项
-    if cli_args.follow_journal {
-        let child = Command::new("journalctl")
-            .args(["-f"])
-            .stdout(Stdio::piped())
-            .spawn()?;
-        let stdout = child.stdout.unwrap();
-        process_stream(BufReader::new(stdout), &engine, &mut writer)?;
-    } else if let Some(path) = cli_args.follow_file {
-        let child = Command::new("tail")
-            .args(["-f", &path.to_string_lossy()])
-            .stdout(Stdio::piped())
-            .spawn()?;
-        let stdout = child.stdout.unwrap();
-        process_stream(BufReader::new(stdout), &engine, &mut writer)?;
-    } else if let Some(path) = cli_args.file {
-        let f = fs::File::open(path)?;
-        process_stream(BufReader::new(f), &engine, &mut writer)?;
+    writer.write_all(engine.document_start()?.as_bytes())?;
+    process_stream(input, &engine, line_filter.as_ref(), &mut writer)?;
+    writer.write_all(engine.document_end().as_bytes())?;
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Opens the input selected by `cli_args`: the output of `journalctl -f`
+/// or `tail -f`, a file, or stdin.
+fn open_input(cli_args: &arg_parser::CliArgs) -> anyhow::Result<Box<dyn BufRead>> {
+    use std::io::{BufReader, IsTerminal};
+    use std::process::Command;
+
+    Ok(if cli_args.follow_journal {
+        Box::new(ChildInput::spawn(Command::new("journalctl").args(["-f"]))?)
+    } else if let Some(path) = cli_args.follow_file.as_ref() {
+        Box::new(ChildInput::spawn(Command::new("tail").arg("-f").arg(path))?)
+    } else if let Some(path) = cli_args.file.as_ref() {
+        Box::new(BufReader::new(std::fs::File::open(path)?))
     } else {
         if std::io::stdin().is_terminal() {
             eprintln!("(Info: Waiting for stdin... Press Ctrl+D to end)");
         }
-        process_stream(BufReader::new(std::io::stdin()), &engine, &mut writer)?;
+        Box::new(BufReader::new(std::io::stdin()))
+    })
+}
+
+/// The output of a child process such as `tail -f`. The child is killed
+/// when the input is dropped, so that it does not outlive an error.
+struct ChildInput {
+    child: std::process::Child,
+    stdout: std::io::BufReader<std::process::ChildStdout>,
+}
+
+impl ChildInput {
+    fn spawn(command: &mut std::process::Command) -> std::io::Result<Self> {
+        let mut child = command.stdout(std::process::Stdio::piped()).spawn()?;
+        let stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+        Ok(Self { child, stdout })
     }
+}
 
-    writer.flush()?;
+impl std::io::Read for ChildInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl BufRead for ChildInput {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.stdout.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.stdout.consume(amount)
+    }
+}
+
+impl Drop for ChildInput {
+    fn drop(&mut self) {
+        // 子进程可能已经退出，错误可以忽略
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Writes one line per preset for `list-presets`: the name, the
+/// description and, for user presets, the file path.
+fn write_preset_list<W: Write>(presets: &[preset::PresetInfo], writer: &mut W) -> std::io::Result<()> {
+    let width = presets.iter().map(|preset| preset.name.len()).max().unwrap_or(0);
+    for preset in presets {
+        write!(writer, "{:<width$}  {}", preset.name, preset.description.as_deref().unwrap_or("-"))?;
+        if let Some(path) = &preset.path {
+            write!(writer, " ({})", path.display())?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Applies `--enable-tag`, `--disable-tag` and `--disable-rule` to a rule
+/// set.
+///
+/// `--enable-tag` only applies to the top-level rules, so that contexts
+/// pushed by the remaining rules keep working. Disabled rules are removed
+/// everywhere.
+///
+/// # Errors
+///
+/// Returns an error if an id, tag or `--filter` selector given on the
+/// command line is not used by any rule, which usually means it was
+/// misspelled.
+fn select_rules(rule_set: &mut rules::RuleSet, cli_args: &arg_parser::CliArgs) -> anyhow::Result<()> {
+    for id in &cli_args.disable_rule {
+        if !rule_set.iter().any(|rule| rule.id.as_ref() == Some(id)) {
+            anyhow::bail!("Unknown rule id '{}'", id);
+        }
+    }
+    for tag in cli_args.enable_tag.iter().chain(&cli_args.disable_tag) {
+        if !rule_set.iter().any(|rule| rule.tags.contains(tag)) {
+            anyhow::bail!("No rule has the tag '{}'", tag);
+        }
+    }
+    for selector in &cli_args.filter {
+        if !rule_set.iter().any(|rule| rule.is_selected_by(selector)) {
+            anyhow::bail!("No rule has the id, tag or kind '{}'", selector);
+        }
+    }
+
+    let disabled = |rule: &rules::Rule| {
+        rule.tags.iter().any(|tag| cli_args.disable_tag.contains(tag))
+            || rule.id.as_ref().is_some_and(|id| cli_args.disable_rule.contains(id))
+    };
+    rule_set.rules.retain(|rule| {
+        let enabled = cli_args.enable_tag.is_empty() || rule.tags.iter().any(|tag| cli_args.enable_tag.contains(tag));
+        enabled && !disabled(rule)
+    });
+    for context in rule_set.contexts.values_mut() {
+        context.rules.retain(|rule| !disabled(rule));
+    }
     Ok(())
 }
 
+/// Which lines [`process_stream`] prints when filtering, like `grep`.
+#[derive(Debug, Clone, Default)]
+struct LineFilter {
+    /// Print the lines that are not matched instead.
+    invert: bool,
+    /// Number of lines to print before each printed match.
+    before: usize,
+    /// Number of lines to print after each printed match.
+    after: usize,
+}
+
+/// Buffers and prints lines according to a [`LineFilter`].
+///
+/// Non-adjacent groups of printed lines are separated by `--` when context
+/// lines are requested, as `grep` does.
+struct FilteredOutput<'a> {
+    filter: &'a LineFilter,
+    /// The most recent unprinted lines, with their line numbers.
+    before: VecDeque<(u64, String)>,
+    /// Number of context lines still to print after the last match.
+    after_left: usize,
+    /// The number of the last printed line.
+    last_printed: Option<u64>,
+}
+
+impl<'a> FilteredOutput<'a> {
+    fn new(filter: &'a LineFilter) -> Self {
+        Self {
+            filter,
+            before: VecDeque::with_capacity(filter.before),
+            after_left: 0,
+            last_printed: None,
+        }
+    }
+
+    /// Handles one rendered line. `matched` tells whether the filter's
+    /// rules matched it, before inversion.
+    fn push<W: Write>(&mut self, printer: &mut LinePrinter<W>, line_no: u64, line: &str, matched: bool) -> std::io::Result<()> {
+        if matched != self.filter.invert {
+            let first = self.before.front().map_or(line_no, |(n, _)| *n);
+            let has_context = self.filter.before > 0 || self.filter.after > 0;
+            if has_context && self.last_printed.is_some_and(|last| first > last + 1) {
+                printer.separator()?;
+            }
+            for (_, before) in self.before.drain(..) {
+                printer.print(&before)?;
+            }
+            printer.print(line)?;
+            self.after_left = self.filter.after;
+            self.last_printed = Some(line_no);
+        } else if self.after_left > 0 {
+            printer.print(line)?;
+            self.after_left -= 1;
+            self.last_printed = Some(line_no);
+        } else if self.filter.before > 0 {
+            if self.before.len() == self.filter.before {
+                self.before.pop_front();
+            }
+            self.before.push_back((line_no, line.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Writes output lines, adding what the output format needs around them.
+///
+/// Records of `--output json` are separated by commas and wrapped in an
+/// array. The `--` separators of [`FilteredOutput`] are only written for
+/// text output, where they cannot be mistaken for data. Formats that
+/// [buffer their output](highlight::HighlightingEngine::buffers_output)
+/// collect all lines and are written by [`LinePrinter::finish`].
+struct LinePrinter<W> {
+    writer: W,
+    /// The lines of a buffered format.
+    buffer: Option<String>,
+    /// Wrap the lines in a JSON array.
+    json_array: bool,
+    /// Write `--` separators.
+    separators: bool,
+    /// Whether a line was written yet.
+    printed: bool,
+}
+
+impl<W: Write> LinePrinter<W> {
+    fn new(writer: W, engine: &highlight::HighlightingEngine) -> std::io::Result<Self> {
+        let format = engine.output_format();
+        let mut printer = Self {
+            writer,
+            buffer: engine.buffers_output().then(String::new),
+            json_array: *format == format::OutputFormat::Json,
+            separators: !format.is_json(),
+            printed: false,
+        };
+        if printer.json_array {
+            printer.writer.write_all(b"[")?;
+        }
+        Ok(printer)
+    }
+
+    fn print(&mut self, line: &str) -> std::io::Result<()> {
+        if self.json_array {
+            self.writer.write_all(if self.printed { b",\n" } else { b"\n" })?;
+        }
+        self.printed = true;
+        match &mut self.buffer {
+            Some(buffer) => {
+                buffer.push_str(line);
+                Ok(())
+            }
+            None => self.writer.write_all(line.as_bytes()),
+        }
+    }
+
+    fn separator(&mut self) -> std::io::Result<()> {
+        if self.separators {
+            match &mut self.buffer {
+                Some(buffer) => buffer.push_str("--\n"),
+                None => self.writer.write_all(b"--\n")?,
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, engine: &highlight::HighlightingEngine) -> anyhow::Result<()> {
+        if self.json_array {
+            self.writer.write_all(b"\n]\n")?;
+        }
+        if let Some(buffer) = &self.buffer {
+            self.writer.write_all(engine.document(buffer)?.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of JSON output, with the spans of [`highlight::HighlightingEngine::spans`].
+#[derive(serde::Serialize)]
+struct LineRecord<'a> {
+    /// The line number, starting at 1.
+    line: u64,
+    /// The text of the line, without the line ending.
+    text: &'a str,
+    spans: Vec<highlight::HighlightSpan<'a>>,
+}
+
 /// Processes a buffered input stream and writes highlighted output.
 ///
 /// This function reads input line by line, applies syntax highlighting,
 /// and writes the result to the provided output writer.
 ///
 /// String buffers are reused across iterations to reduce allocations.
+/// If the engine has colors disabled, lines are passed through untouched.
+///
+/// With a `filter`, only the lines it selects are written. Every line is
+/// still matched in that case, even with colors disabled.
+///
+/// For JSON output formats, each line is written as a [`LineRecord`]
+/// instead of rendered text.
+///
+/// Escape sequences of the input are handled as the engine's
+/// [`ansi::AnsiMode`] says. Unless they are passed through, rules match
+/// the visible text only. Preserved styles lie beneath the styles of the
+/// rules, and other preserved sequences are put back into ANSI output.
 ///
 /// # Errors
 ///
@@ -169,39 +546,92 @@ pub fn run(cli_args: arg_parser::CliArgs) -> anyhow::Result<()> {
 fn process_stream<R: BufRead, W: Write>(
     mut reader: R,
     engine: &highlight::HighlightingEngine,
+    filter: Option<&LineFilter>,
     writer: &mut W,
 ) -> anyhow::Result<()> {
     let mut line_buffer = String::new();
     let mut out_buffer = String::new();
+    let mut state = highlight::LineState::default();
+    let mut filtered = filter.map(FilteredOutput::new);
+    let mut printer = LinePrinter::new(writer, engine)?;
+    let mode = engine.ansi_mode();
+    let mut parser = mode.map(|_| ansi::AnsiParser::default());
+    let mut line_no = 0;
 
     // 循环复用 String 内存，避免每行都分配内存
     while reader.read_line(&mut line_buffer)? > 0 {
-        engine.render_line(&line_buffer, &mut out_buffer);
-        writer.write_all(out_buffer.as_bytes())?;
+        line_no += 1;
+        let mut styled = parser.as_mut().and_then(|parser| parser.parse_line(&line_buffer));
+        if mode == Some(ansi::AnsiMode::Strip)
+            && let Some(styled) = styled.as_mut()
+        {
+            styled.spans.clear();
+            styled.escapes.clear();
+        }
+        let line: &str = if engine.output_format().is_json() {
+            let (text, input_spans) = match &styled {
+                Some(styled) => (styled.text.as_str(), styled.spans.as_slice()),
+                None => (line_buffer.as_str(), &[][..]),
+            };
+            // 输入自带的样式位于最底层，排在最前面
+            let record = LineRecord {
+                line: line_no,
+                text: text.trim_end_matches(['\n', '\r']),
+                spans: input_spans.iter().map(Into::into).chain(engine.spans(text, &mut state)).collect(),
+            };
+            out_buffer = serde_json::to_string(&record)?;
+            if *engine.output_format() == format::OutputFormat::JsonLines {
+                out_buffer.push('\n');
+            }
+            &out_buffer
+        } else if let Some(styled) = &styled {
+            engine.render_styled_line(styled, &mut state, &mut out_buffer);
+            // 关闭颜色时不输出任何转义序列
+            if !engine.output_format().interprets_escapes() && !engine.is_plain() {
+                ansi::insert_escapes(&mut out_buffer, &styled.escapes);
+            }
+            &out_buffer
+        } else if filtered.is_none() && engine.is_plain() {
+            &line_buffer
+        } else {
+            // 过滤时必须匹配每一行；关闭颜色时渲染结果与原文相同
+            engine.render_line(&line_buffer, &mut state, &mut out_buffer);
+            &out_buffer
+        };
+
+        match filtered.as_mut() {
+            Some(filtered) => filtered.push(&mut printer, line_no, line, state.matched())?,
+            None => printer.print(line)?,
+        }
         line_buffer.clear();
     }
+    printer.finish(engine)?;
     Ok(())
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::highlight::HighlightingEngine;
-    use crate::rules::{Color, Rule};
+    use crate::arg_parser::load_rules_from_file;
+    use crate::highlight::{EngineOptions, HighlightingEngine, LineState};
+    use crate::rules::{rgb_to_ansi16, rgb_to_ansi256, Attribute, CaptureStyle, Color, Context, Rule, RuleSet, Scope, Style};
+    use crate::term::{colors_enabled_from_env, ColorDepth};
+    use crate::theme::Theme;
 
     #[test]
     fn rule_level_ignore_case_works() {
         let rules = vec![Rule {
             keyword: "error".into(),
-            color: Color::Preset { name: "Red".into() },
+            color: Some(Color::Preset { name: "Red".into() }),
             is_regex: false,
             ignore_case: true,
+            ..Default::default()
         }];
 
         let engine = HighlightingEngine::new(&rules, false).unwrap();
         let mut out = String::new();
 
-        engine.render_line("ERROR\n", &mut out);
+        engine.render_line("ERROR\n", &mut LineState::default(), &mut out);
         assert!(out.contains("\x1b[31mERROR\x1b[0m"));
     }
 
@@ -209,15 +639,16 @@ mod tests {
     fn cli_ignore_case_overrides_rules() {
         let rules = vec![Rule {
             keyword: "error".into(),
-            color: Color::Preset { name: "Red".into() },
+            color: Some(Color::Preset { name: "Red".into() }),
             is_regex: false,
             ignore_case: false,
+            ..Default::default()
         }];
 
         let engine = HighlightingEngine::new(&rules, true).unwrap();
         let mut out = String::new();
 
-        engine.render_line("ERROR\n", &mut out);
+        engine.render_line("ERROR\n", &mut LineState::default(), &mut out);
         assert!(out.contains("\x1b[31mERROR\x1b[0m"));
     }
 
@@ -225,15 +656,967 @@ mod tests {
     fn case_sensitive_rule_does_not_match() {
         let rules = vec![Rule {
             keyword: "error".into(),
-            color: Color::Preset { name: "Red".into() },
+            color: Some(Color::Preset { name: "Red".into() }),
             is_regex: false,
             ignore_case: false,
+            ..Default::default()
         }];
 
         let engine = HighlightingEngine::new(&rules, false).unwrap();
         let mut out = String::new();
 
-        engine.render_line("ERROR\n", &mut out);
+        engine.render_line("ERROR\n", &mut LineState::default(), &mut out);
         assert!(!out.contains("\x1b[31m"));
     }
+
+    #[test]
+    fn style_attributes_and_colors_are_combined() {
+        let rules = vec![Rule {
+            keyword: "FATAL".into(),
+            color: Some(Color::Preset { name: "Red".into() }),
+            style: Style {
+                background: Some(Color::RGB { r: 1, g: 2, b: 3 }),
+                attributes: vec![Attribute::Bold, Attribute::Underline],
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+
+        let engine = HighlightingEngine::new(&rules, false).unwrap();
+        let mut out = String::new();
+
+        engine.render_line("FATAL\n", &mut LineState::default(), &mut out);
+        assert_eq!(out, "\x1b[1;4;31;48;2;1;2;3mFATAL\x1b[0m\n");
+    }
+
+    #[test]
+    fn background_shorthand_is_rendered() {
+        let rules = vec![Rule {
+            keyword: "CRITICAL".into(),
+            color: Some(Color::Preset { name: "Yellow".into() }),
+            background: Some(Color::Preset { name: "Red".into() }),
+            ..Default::default()
+        }];
+
+        let engine = HighlightingEngine::new(&rules, false).unwrap();
+        let mut out = String::new();
+
+        engine.render_line("CRITICAL\n", &mut LineState::default(), &mut out);
+        assert_eq!(out, "\x1b[33;41mCRITICAL\x1b[0m\n");
+    }
+
+    #[test]
+    fn rgb_is_downgraded_to_color_depth() {
+        let rules = vec![Rule {
+            keyword: "x".into(),
+            color: Some(Color::RGB { r: 255, g: 165, b: 0 }),
+            ..Default::default()
+        }];
+        let render = |depth| {
+            let options = EngineOptions {
+                color_depth: depth,
+                ..Default::default()
+            };
+            let engine = HighlightingEngine::with_options(&rules, &options).unwrap();
+            engine.highlight_line("x")
+        };
+
+        assert_eq!(render(ColorDepth::TrueColor), "\x1b[38;2;255;165;0mx\x1b[0m");
+        assert_eq!(render(ColorDepth::Ansi256), "\x1b[38;5;214mx\x1b[0m");
+        assert_eq!(render(ColorDepth::Ansi16), "\x1b[33mx\x1b[0m");
+        assert_eq!(render(ColorDepth::None), "x");
+    }
+
+    #[test]
+    fn grays_map_to_grayscale_ramp() {
+        assert_eq!(rgb_to_ansi256(180, 180, 180), 249);
+        assert_eq!(rgb_to_ansi256(0, 0, 0), 16);
+        assert_eq!(rgb_to_ansi16(200, 0, 0), 1);
+    }
+
+    #[test]
+    fn colors_follow_no_color_and_clicolor_force() {
+        assert!(colors_enabled_from_env(None, None, true));
+        assert!(!colors_enabled_from_env(None, None, false));
+        assert!(!colors_enabled_from_env(None, Some("1"), true));
+        assert!(colors_enabled_from_env(None, Some(""), true));
+        assert!(colors_enabled_from_env(Some("1"), Some("1"), false));
+        assert!(!colors_enabled_from_env(Some("0"), None, false));
+    }
+
+    #[test]
+    fn color_depth_is_detected_from_env() {
+        let depth = ColorDepth::from_env(Some("truecolor"), Some("xterm-256color"));
+        assert_eq!(depth, ColorDepth::TrueColor);
+        assert_eq!(ColorDepth::from_env(None, Some("screen-256color")), ColorDepth::Ansi256);
+        assert_eq!(ColorDepth::from_env(None, Some("vt100")), ColorDepth::Ansi16);
+        assert_eq!(ColorDepth::from_env(None, Some("dumb")), ColorDepth::None);
+    }
+
+    #[test]
+    fn color_always_overrides_a_colorless_terminal() {
+        use crate::arg_parser::ColorChoice;
+
+        let dumb = ColorDepth::from_env(None, Some("dumb"));
+        assert_eq!(ColorChoice::Always.resolve_depth(dumb), ColorDepth::Ansi16);
+        assert_eq!(ColorChoice::Always.resolve_depth(ColorDepth::Ansi256), ColorDepth::Ansi256);
+        assert_eq!(ColorChoice::Never.resolve_depth(ColorDepth::TrueColor), ColorDepth::None);
+    }
+
+    #[test]
+    fn extended_palette_and_indexed_colors() {
+        let sgr = |color: Color| color.sgr_params(false, ColorDepth::TrueColor).unwrap();
+
+        assert_eq!(sgr(Color::Preset { name: "BrightBlack".into() }), "90");
+        assert_eq!(sgr(Color::Preset { name: "bright_white".into() }), "97");
+        assert_eq!(sgr(Color::Preset { name: "SteelBlue".into() }), "38;2;70;130;180");
+        assert_eq!(sgr(Color::Indexed { index: 208 }), "38;5;208");
+        assert!(Color::Preset { name: "NotAColor".into() }
+            .sgr_params(false, ColorDepth::TrueColor)
+            .is_err());
+
+        let indexed = Color::Indexed { index: 196 };
+        assert_eq!(indexed.sgr_params(true, ColorDepth::Ansi16).unwrap(), "101");
+    }
+
+    #[test]
+    fn colors_parse_from_strings() {
+        let rgb = |s: &str| match s.parse::<Color>().unwrap() {
+            Color::RGB { r, g, b } => (r, g, b),
+            other => panic!("unexpected color {:?}", other),
+        };
+
+        assert_eq!(rgb("#6a9955"), (106, 153, 85));
+        assert_eq!(rgb("#fff"), (255, 255, 255));
+        assert_eq!(rgb("rgb(106, 153, 85)"), (106, 153, 85));
+        assert!(matches!("red".parse::<Color>().unwrap(), Color::Preset { .. }));
+
+        assert!("#12345".parse::<Color>().is_err());
+        assert!("rgb(1, 2, 300)".parse::<Color>().is_err());
+        assert!("redd".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn yaml_string_colors_report_clear_errors() {
+        let rule: Rule = serde_yml::from_str("keyword: x\ncolor: \"#6a9955\"").unwrap();
+        assert!(matches!(rule.color, Some(Color::RGB { r: 106, g: 153, b: 85 })));
+
+        let err = serde_yml::from_str::<Rule>("keyword: x\ncolor: \"#6a99\"").unwrap_err();
+        assert!(err.to_string().contains("Invalid hex color '#6a99'"));
+    }
+
+    #[test]
+    fn palette_references_resolve_across_includes() {
+        let dir = std::env::temp_dir().join(format!("highlite-palette-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("base.yaml"),
+            "palette:\n  accent: \"#ffa500\"\n  warn: { ref: accent }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("main.yaml"),
+            "include: [base.yaml]\nrules:\n  - keyword: x\n    color: { ref: warn }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("broken.yaml"),
+            "rules:\n  - keyword: y\n    color: { ref: missing }\n",
+        )
+        .unwrap();
+
+        let rules = load_rules_from_file(dir.join("main.yaml")).unwrap();
+        assert!(matches!(rules[0].color, Some(Color::RGB { r: 255, g: 165, b: 0 })));
+
+        let err = load_rules_from_file(dir.join("broken.yaml")).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined palette color 'missing' in rule 'y'");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rule_kinds_are_styled_by_theme() {
+        let theme = Theme::load("dark").unwrap();
+        assert!(theme.style_for("level.error").is_some());
+        assert!(theme.style_for("level.error.auth").is_some());
+        assert!(theme.style_for("nonexistent").is_none());
+
+        let rules = vec![
+            Rule {
+                keyword: "ERROR".into(),
+                kind: Some("level.error".into()),
+                ..Default::default()
+            },
+            Rule {
+                keyword: "WARN".into(),
+                kind: Some("level.warn".into()),
+                style: Style {
+                    attributes: vec![Attribute::Bold],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        let options = EngineOptions {
+            theme,
+            ..Default::default()
+        };
+        let engine = HighlightingEngine::with_options(&rules, &options).unwrap();
+
+        assert_eq!(engine.highlight_line("ERROR"), "\x1b[31mERROR\x1b[0m");
+        assert_eq!(engine.highlight_line("WARN"), "\x1b[1;33mWARN\x1b[0m");
+    }
+
+    #[test]
+    fn capture_groups_are_styled_individually() {
+        let rules = vec![Rule {
+            keyword: r"\b(user)=(?P<value>\S+)".into(),
+            is_regex: true,
+            color: Some(Color::Preset { name: "Cyan".into() }),
+            captures: [
+                ("1".to_string(), CaptureStyle {
+                    color: Some(Color::Preset { name: "Blue".into() }),
+                    ..Default::default()
+                }),
+                ("value".to_string(), CaptureStyle {
+                    style: Style {
+                        attributes: vec![Attribute::Bold],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }),
+            ]
+            .into(),
+            ..Default::default()
+        }];
+
+        let engine = HighlightingEngine::new(&rules, false).unwrap();
+        assert_eq!(
+            engine.highlight_line("x user=john"),
+            "x \x1b[34muser\x1b[0m\x1b[36m=\x1b[0m\x1b[1;36mjohn\x1b[0m"
+        );
+
+        let mut missing = rules.clone();
+        missing[0].captures.insert("nope".into(), CaptureStyle::default());
+        assert!(HighlightingEngine::new(&missing, false).is_err());
+    }
+
+    #[test]
+    fn overlay_layers_compose_with_base_layer() {
+        let rules = vec![
+            Rule {
+                keyword: "//.*".into(),
+                is_regex: true,
+                color: Some(Color::Preset { name: "Green".into() }),
+                ..Default::default()
+            },
+            Rule {
+                keyword: "TODO".into(),
+                layer: 1,
+                style: Style {
+                    attributes: vec![Attribute::Bold],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+
+        let engine = HighlightingEngine::new(&rules, false).unwrap();
+        assert_eq!(
+            engine.highlight_line("x // TODO: y"),
+            "x \x1b[32m// \x1b[0m\x1b[1;32mTODO\x1b[0m\x1b[32m: y\x1b[0m"
+        );
+        assert_eq!(engine.highlight_line("TODO"), "\x1b[1mTODO\x1b[0m");
+    }
+
+    #[test]
+    fn regions_continue_across_lines() {
+        let rules = vec![
+            Rule {
+                keyword: r"/\*".into(),
+                end: Some(r"\*/".into()),
+                is_regex: true,
+                color: Some(Color::Preset { name: "Green".into() }),
+                ..Default::default()
+            },
+            Rule {
+                keyword: r"<<(\w+)".into(),
+                end: Some(r"^\1$".into()),
+                is_regex: true,
+                color: Some(Color::Preset { name: "Yellow".into() }),
+                ..Default::default()
+            },
+        ];
+        let engine = HighlightingEngine::new(&rules, false).unwrap();
+
+        let render = |lines: &[&str]| {
+            let mut state = LineState::default();
+            let mut out = String::new();
+            lines
+                .iter()
+                .map(|line| {
+                    engine.render_line(line, &mut state, &mut out);
+                    out.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            render(&["a /* b\n", "c\n", "d */ e\n", "f\n"]),
+            [
+                "a \x1b[32m/* b\x1b[0m\n",
+                "\x1b[32mc\x1b[0m\n",
+                "\x1b[32md */\x1b[0m e\n",
+                "f\n",
+            ]
+        );
+        assert_eq!(render(&["/* a */ b"]), ["\x1b[32m/* a */\x1b[0m b"]);
+        assert_eq!(
+            render(&["cat <<EOF\n", "END\n", "EOF\n", "x\n"]),
+            [
+                "cat \x1b[33m<<EOF\x1b[0m\n",
+                "\x1b[33mEND\x1b[0m\n",
+                "\x1b[33mEOF\x1b[0m\n",
+                "x\n",
+            ]
+        );
+        // 无状态的 highlight_line 不会延续区域
+        assert_eq!(engine.highlight_line("c"), "c");
+
+        // 结束模式引用了开始模式中不存在的捕获组
+        let missing = Rule { keyword: "a(b)".into(), end: Some(r"\3".into()), is_regex: true, ..Default::default() };
+        let err = HighlightingEngine::new(&[missing], false).err().unwrap();
+        assert_eq!(err.to_string(), "Rule 'a(b)' has no capture group 3");
+
+        // 转义的反斜杠之后的数字不是反向引用
+        let escaped = Rule {
+            keyword: "<".into(),
+            end: Some(r"\\1>".into()),
+            is_regex: true,
+            color: Some(Color::Preset { name: "Green".into() }),
+            ..Default::default()
+        };
+        let engine = HighlightingEngine::new(&[escaped], false).unwrap();
+        let mut out = String::new();
+        engine.render_line("< a 1> \\1> b\n", &mut LineState::default(), &mut out);
+        assert_eq!(out, "\x1b[32m< a 1> \\1>\x1b[0m b\n");
+    }
+
+    #[test]
+    fn contexts_push_and_pop() {
+        let green = || Some(Color::Preset { name: "Green".into() });
+        let rule_set = RuleSet {
+            rules: vec![
+                Rule {
+                    keyword: "\"".into(),
+                    push: Some("string".into()),
+                    color: green(),
+                    ..Default::default()
+                },
+                Rule {
+                    keyword: r"\bif\b".into(),
+                    is_regex: true,
+                    color: Some(Color::Preset { name: "Blue".into() }),
+                    ..Default::default()
+                },
+            ],
+            contexts: [(
+                "string".to_string(),
+                Context {
+                    color: green(),
+                    rules: vec![
+                        Rule {
+                            keyword: r"\\.".into(),
+                            is_regex: true,
+                            color: Some(Color::Preset { name: "Yellow".into() }),
+                            ..Default::default()
+                        },
+                        Rule {
+                            keyword: "\"".into(),
+                            pop: true,
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+        let engine = HighlightingEngine::with_rule_set(&rule_set, &EngineOptions::default()).unwrap();
+
+        assert_eq!(
+            engine.highlight_line(r#"if "if \" x" if"#),
+            "\x1b[34mif\x1b[0m \x1b[32m\"if \x1b[0m\x1b[33m\\\"\x1b[0m\x1b[32m x\"\x1b[0m \x1b[34mif\x1b[0m"
+        );
+
+        // 上下文跨行保持
+        let mut state = LineState::default();
+        let mut out = String::new();
+        engine.render_line("\"if\n", &mut state, &mut out);
+        assert_eq!(out, "\x1b[32m\"if\x1b[0m\n");
+        engine.render_line("x\" if\n", &mut state, &mut out);
+        assert_eq!(out, "\x1b[32mx\"\x1b[0m \x1b[34mif\x1b[0m\n");
+
+        let mut undefined = rule_set.clone();
+        undefined.rules[0].push = Some("nope".into());
+        assert!(HighlightingEngine::with_rule_set(&undefined, &EngineOptions::default()).is_err());
+    }
+
+    #[test]
+    fn line_rules_style_whole_line() {
+        let rules = vec![
+            Rule {
+                keyword: "ERROR".into(),
+                scope: Scope::Line,
+                color: Some(Color::Preset { name: "Red".into() }),
+                ..Default::default()
+            },
+            Rule {
+                keyword: "DEBUG".into(),
+                scope: Scope::Line,
+                style: Style {
+                    attributes: vec![Attribute::Dim],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            Rule {
+                keyword: "user".into(),
+                color: Some(Color::Preset { name: "Blue".into() }),
+                ..Default::default()
+            },
+        ];
+
+        let engine = HighlightingEngine::new(&rules, false).unwrap();
+        assert_eq!(
+            engine.highlight_line("ERROR user x\n"),
+            "\x1b[31mERROR \x1b[0m\x1b[34muser\x1b[0m\x1b[31m x\x1b[0m\n"
+        );
+        assert_eq!(
+            engine.highlight_line("x DEBUG user\n"),
+            "\x1b[2mx DEBUG \x1b[0m\x1b[2;34muser\x1b[0m\n"
+        );
+        // 多条整行规则匹配时第一条生效
+        assert_eq!(engine.highlight_line("DEBUG ERROR"), "\x1b[31mDEBUG ERROR\x1b[0m");
+        assert_eq!(engine.highlight_line("user"), "\x1b[34muser\x1b[0m");
+    }
+
+    #[test]
+    fn filter_prints_matching_lines_with_context() {
+        let rules = vec![
+            Rule {
+                keyword: "ERROR".into(),
+                kind: Some("level.error".into()),
+                ..Default::default()
+            },
+            Rule {
+                keyword: "WARN".into(),
+                kind: Some("level.warn".into()),
+                ..Default::default()
+            },
+        ];
+        let input = "a\nERROR 1\nb\nc\nd\nWARN 2\ne\n";
+
+        let run = |filter: &[&str], line_filter: super::LineFilter| {
+            let options = EngineOptions {
+                color_depth: ColorDepth::None,
+                filter: filter.iter().map(|f| f.to_string()).collect(),
+                ..Default::default()
+            };
+            let engine = HighlightingEngine::with_options(&rules, &options).unwrap();
+            let mut out = Vec::new();
+            super::process_stream(input.as_bytes(), &engine, Some(&line_filter), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(run(&[], Default::default()), "ERROR 1\nWARN 2\n");
+        assert_eq!(run(&["level.error"], Default::default()), "ERROR 1\n");
+        assert_eq!(run(&["level"], Default::default()), "ERROR 1\nWARN 2\n");
+        assert_eq!(
+            run(&["level"], super::LineFilter { invert: true, ..Default::default() }),
+            "a\nb\nc\nd\ne\n"
+        );
+        assert_eq!(
+            run(&[], super::LineFilter { before: 1, after: 1, ..Default::default() }),
+            "a\nERROR 1\nb\n--\nd\nWARN 2\ne\n"
+        );
+        assert_eq!(
+            run(&[], super::LineFilter { before: 2, ..Default::default() }),
+            "a\nERROR 1\n--\nc\nd\nWARN 2\n"
+        );
+    }
+
+    #[test]
+    fn rules_can_be_disabled_by_id_and_tag() {
+        use crate::arg_parser::CliArgs;
+        use clap::Parser;
+
+        let rule = |id: &str, tag: &str| Rule {
+            id: Some(id.into()),
+            tags: vec![tag.into()],
+            keyword: id.into(),
+            ..Default::default()
+        };
+        let rule_set = RuleSet::from(vec![rule("get", "http"), rule("404", "http"), rule("select", "sql")]);
+
+        let select = |args: &[&str]| {
+            let cli_args = CliArgs::parse_from(std::iter::once("highlite").chain(args.iter().copied()));
+            let mut rule_set = rule_set.clone();
+            super::select_rules(&mut rule_set, &cli_args).map(|_| {
+                rule_set.rules.iter().map(|r| r.keyword.clone()).collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(select(&["--disable-tag", "sql"]).unwrap(), ["get", "404"]);
+        assert_eq!(select(&["--disable-rule", "404"]).unwrap(), ["get", "select"]);
+        assert_eq!(select(&["--enable-tag", "sql"]).unwrap(), ["select"]);
+        assert!(select(&["--disable-rule", "nope"]).is_err());
+        assert!(select(&["--enable-tag", "nope"]).is_err());
+        assert!(select(&["--filter", "http", "--filter", "select"]).is_ok());
+        assert!(select(&["--filter", "typo"]).is_err());
+
+        let error = Rule { kind: Some("level.error".into()), ..Default::default() };
+        assert!(error.is_selected_by("level") && error.is_selected_by("level.error"));
+        assert!(!error.is_selected_by("lev"));
+
+        let mut duplicate = rule_set.clone();
+        duplicate.rules.push(rule("get", "other"));
+        assert!(HighlightingEngine::with_rule_set(&duplicate, &EngineOptions::default()).is_err());
+    }
+
+    #[test]
+    fn configs_extend_presets() {
+        let dir = std::env::temp_dir().join(format!("highlite-extends-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("main.yaml"),
+            "extends: logs\n\
+             remove: [sql]\n\
+             overrides:\n  level-error: { color: magenta }\n\
+             rules:\n  - id: number\n    keyword: '\\d+'\n    is_regex: true\n  - id: extra\n    keyword: x\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("include.yaml"), "include: ['preset:cpp']\n").unwrap();
+        std::fs::write(dir.join("broken.yaml"), "extends: logs\nremove: [nope]\n").unwrap();
+
+        let preset = crate::preset::get_preset("logs").unwrap().rules;
+        let rules = load_rules_from_file(dir.join("main.yaml")).unwrap();
+        let ids: Vec<_> = rules.iter().filter_map(|r| r.id.as_deref()).collect();
+
+        assert_eq!(rules.len(), preset.len());
+        assert!(!ids.contains(&"sql"));
+        assert_eq!(ids.last(), Some(&"extra"));
+
+        let level_error = rules.iter().find(|r| r.id.as_deref() == Some("level-error")).unwrap();
+        assert!(matches!(level_error.color, Some(Color::Preset { .. })));
+        assert_eq!(level_error.kind.as_deref(), Some("level.error"));
+
+        let number_idx = preset.iter().position(|r| r.id.as_deref() == Some("number")).unwrap();
+        assert_eq!(rules[number_idx - 1].keyword, r"\d+");
+
+        let rule_set = crate::arg_parser::load_rule_set_from_file(dir.join("include.yaml")).unwrap();
+        assert!(rule_set.contexts.contains_key("string"));
+
+        let err = load_rules_from_file(dir.join("broken.yaml")).unwrap_err();
+        assert!(err.to_string().starts_with("Cannot remove unknown rule 'nope'"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn config_discovery_order() {
+        use crate::arg_parser::{config_home_from, discover_config_from};
+
+        let dir = std::env::temp_dir().join(format!("highlite-discover-{}", std::process::id()));
+        let repo = dir.join("repo");
+        let cwd = repo.join("src/nested");
+        let home = dir.join("home");
+        std::fs::create_dir_all(&cwd).unwrap();
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::write(home.join("config.yaml"), "rules: []\n").unwrap();
+        // 仓库之外的项目配置不会被使用
+        std::fs::write(dir.join(".highlite.yaml"), "rules: []\n").unwrap();
+
+        let discover = |env: Option<&str>| discover_config_from(env.map(Into::into), Some(&cwd), Some(home.clone()));
+
+        assert_eq!(discover(None), Some(home.join("config.yaml")));
+        std::fs::write(repo.join(".highlite.yaml"), "rules: []\n").unwrap();
+        assert_eq!(discover(None), Some(repo.join(".highlite.yaml")));
+        assert_eq!(discover(Some("/x.yaml")), Some("/x.yaml".into()));
+        assert_eq!(discover(Some("")), Some(repo.join(".highlite.yaml")));
+
+        assert_eq!(
+            config_home_from(Some("/xdg".into()), Some("/home/u".into())),
+            Some("/xdg/highlite".into())
+        );
+        assert_eq!(
+            config_home_from(Some("relative".into()), Some("/home/u".into())),
+            Some("/home/u/.config/highlite".into())
+        );
+        assert_eq!(config_home_from(None, None), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn user_presets_are_found_by_name() {
+        use crate::preset::find_preset;
+
+        let dir = std::env::temp_dir().join(format!("highlite-presets-{}", std::process::id()));
+        let first = dir.join("first");
+        let second = dir.join("second");
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+        std::fs::write(first.join("team.yaml"), "rules:\n  - keyword: first\n").unwrap();
+        std::fs::write(second.join("team.yaml"), "rules:\n  - keyword: second\n").unwrap();
+        std::fs::write(second.join("logs.yaml"), "rules: []\n").unwrap();
+
+        let search_dirs = [first.clone(), second.clone()];
+        assert_eq!(find_preset("team", &search_dirs).unwrap().rules[0].keyword, "first");
+        assert_eq!(find_preset("team", &search_dirs[1..]).unwrap().rules[0].keyword, "second");
+        // 内置预设优先
+        assert!(!find_preset("logs", &search_dirs).unwrap().rules.is_empty());
+        assert!(find_preset("missing", &search_dirs).is_err());
+        assert!(find_preset("../second/team", &search_dirs).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn presets_are_detected_from_input() {
+        use crate::detect::{DetectConfig, detect_preset};
+        use std::path::Path;
+
+        let defaults = DetectConfig::default();
+        let detect = |path: Option<&str>, head: &str| detect_preset(path.map(Path::new), head.as_bytes(), &defaults);
+
+        assert_eq!(detect(Some("src/main.CPP"), "").as_deref(), Some("cpp"));
+        assert_eq!(detect(Some("data.json"), "plain text").as_deref(), Some("json"));
+        assert_eq!(detect(Some("/var/log/syslog"), "").as_deref(), Some("logs"));
+        assert_eq!(detect(None, "  {\"a\": 1}\n").as_deref(), Some("json"));
+        assert_eq!(detect(None, "[\n  {\"a\": 1}\n]\n").as_deref(), Some("json"));
+        assert_eq!(detect(None, "[main] INFO started\n").as_deref(), None);
+        assert_eq!(detect(None, "<34>Oct 11 22:14:15 host su: failed\n").as_deref(), Some("logs"));
+        assert_eq!(detect(None, "Mar 21 10:15:44 host sshd[42]: ok\n").as_deref(), Some("logs"));
+        assert_eq!(detect(None, "// header\n#include <vector>\n{\n").as_deref(), Some("cpp"));
+        // 代码块的 "{" 不是 JSON
+        assert_eq!(detect(None, "int main()\n{\n  if (x) return 0;\n}\n").as_deref(), None);
+        assert_eq!(detect(None, "{ return x; }\n").as_deref(), None);
+        assert_eq!(detect(None, "\n{\n  \"a\": 1\n}\n").as_deref(), Some("json"));
+        assert_eq!(detect(Some("notes.txt"), "hello\n").as_deref(), None);
+
+        // 用户映射优先于内置映射，解释器只使用用户映射
+        let config: DetectConfig = serde_yml::from_str(
+            "extensions: { log: backend, trace: backend }\ninterpreters: { python3: python }\n",
+        )
+        .unwrap();
+        let detect = |path: Option<&str>, head: &str| detect_preset(path.map(Path::new), head.as_bytes(), &config);
+        assert_eq!(detect(Some("app.log"), "").as_deref(), Some("backend"));
+        assert_eq!(detect(Some("run.TRACE"), "").as_deref(), Some("backend"));
+        assert_eq!(detect(None, "#!/usr/bin/env -S python3 -u\n").as_deref(), Some("python"));
+        assert_eq!(detect(None, "#!/bin/sh\n").as_deref(), None);
+    }
+
+    #[test]
+    fn dumped_presets_load_back() {
+        use crate::arg_parser::load_rule_set_from_file;
+
+        let dir = std::env::temp_dir().join(format!("highlite-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = EngineOptions { color_depth: ColorDepth::TrueColor, ..Default::default() };
+        let sample = "2024-01-02 10:00:00 ERROR [main] GET /api?id=\"x\" 500 {\"k\": 1} /* c */ int x = 'a'; // TODO\n";
+
+        for name in ["logs", "json", "cpp"] {
+            let preset = crate::preset::get_preset(name).unwrap();
+            let path = dir.join(format!("{}.yaml", name));
+            std::fs::write(&path, serde_yml::to_string(&preset).unwrap()).unwrap();
+            let loaded = load_rule_set_from_file(&path).unwrap();
+
+            let expected = HighlightingEngine::with_rule_set(&preset, &options).unwrap();
+            let actual = HighlightingEngine::with_rule_set(&loaded, &options).unwrap();
+            assert_eq!(actual.highlight_line(sample), expected.highlight_line(sample), "preset {}", name);
+        }
+
+        // 各种颜色写法都能原样读回
+        let colors = "rules:\n  - keyword: a\n    color: \"#6a9955\"\n    background: { index: 208 }\n    \
+                      style: { attributes: [bold] }\n    scope: line\n";
+        std::fs::write(dir.join("colors.yaml"), colors).unwrap();
+        let rule_set = load_rule_set_from_file(dir.join("colors.yaml")).unwrap();
+        let dumped = serde_yml::to_string(&rule_set).unwrap();
+        assert_eq!(
+            dumped,
+            "rules:\n- keyword: a\n  color: '#6a9955'\n  background:\n    index: 208\n  style:\n    attributes:\n    - bold\n  scope: line\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn html_output_escapes_and_styles_spans() {
+        use crate::format::{HtmlOptions, OutputFormat};
+
+        let rules = vec![
+            Rule {
+                keyword: "<err>".into(),
+                color: Some(Color::Preset { name: "Red".into() }),
+                style: Style { attributes: vec![Attribute::Bold], ..Default::default() },
+                ..Default::default()
+            },
+            Rule {
+                keyword: "ok".into(),
+                style: Style { attributes: vec![Attribute::Reverse], ..Default::default() },
+                ..Default::default()
+            },
+        ];
+        let engine = |options: HtmlOptions| {
+            let options = EngineOptions { output: OutputFormat::Html(options), ..Default::default() };
+            HighlightingEngine::with_rule_set(&RuleSet::from(rules.clone()), &options).unwrap()
+        };
+
+        let inline = engine(HtmlOptions::default());
+        assert_eq!(
+            inline.highlight_line("a <err> & ok\n"),
+            "a <span style=\"color: #cd0000; font-weight: bold\">&lt;err&gt;</span> &amp; \
+             <span style=\"color: #1e1e1e; background-color: #d4d4d4\">ok</span>\n"
+        );
+        assert!(inline.document_start().unwrap().starts_with("<pre class=\"highlite\" style="));
+        assert_eq!(inline.document_end(), "</pre>\n");
+
+        let classes = engine(HtmlOptions { classes: true, standalone: true });
+        assert_eq!(
+            classes.highlight_line("<err>"),
+            "<span class=\"hl-fg-cd0000 hl-bold\">&lt;err&gt;</span>"
+        );
+        let header = classes.document_start().unwrap();
+        assert!(header.starts_with("<!DOCTYPE html>"));
+        assert!(header.contains(".hl-fg-cd0000 { color: #cd0000; }"));
+        assert!(header.ends_with("<pre class=\"highlite\">"));
+        assert!(classes.document_end().ends_with("</html>\n"));
+    }
+
+    #[test]
+    fn json_output_reports_spans() {
+        use crate::format::OutputFormat;
+
+        let rule_set = RuleSet {
+            rules: vec![
+                Rule { id: Some("error".into()), keyword: "ERROR".into(), kind: Some("level.error".into()), ..Default::default() },
+                Rule { keyword: "\"".into(), push: Some("string".into()), ..Default::default() },
+            ],
+            contexts: [(
+                "string".to_string(),
+                Context {
+                    kind: Some("string".into()),
+                    rules: vec![Rule { keyword: "\"".into(), pop: true, ..Default::default() }],
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+        let render = |output: OutputFormat, filter: Option<&super::LineFilter>| {
+            let options = EngineOptions { output, ..Default::default() };
+            let engine = HighlightingEngine::with_rule_set(&rule_set, &options).unwrap();
+            let mut out = Vec::new();
+            super::process_stream("ERROR \"a\nb\" ok\nplain\n".as_bytes(), &engine, filter, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&render(OutputFormat::Json, None)).unwrap();
+        let records = json.as_array().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["text"], "ERROR \"a");
+        let spans = records[0]["spans"].as_array().unwrap();
+        assert_eq!(spans[0]["id"], "error");
+        assert_eq!((&spans[0]["start"], &spans[0]["end"], &spans[0]["rule"]), (&0.into(), &5.into(), &0.into()));
+        // 上下文中未匹配的文本带有上下文名称，延续到下一行
+        assert_eq!(spans[2]["context"], "string");
+        assert_eq!(records[1]["spans"][0]["context"], "string");
+        assert_eq!(records[1]["spans"][1]["rule"], 2);
+        assert!(records[2]["spans"].as_array().unwrap().is_empty());
+
+        // 过滤同样适用于 JSON 输出
+        let filter = super::LineFilter { before: 0, after: 0, invert: true };
+        let lines = render(OutputFormat::JsonLines, Some(&filter));
+        assert_eq!(lines, "{\"line\":3,\"text\":\"plain\",\"spans\":[]}\n");
+        assert_eq!(render(OutputFormat::Json, Some(&filter)), format!("[\n{}\n]\n", lines.trim_end()));
+    }
+
+    #[test]
+    fn ansi_parser_follows_sgr_sequences() {
+        use crate::ansi::{AnsiParser, StyledText};
+
+        let mut parser = AnsiParser::default();
+        let pieces = parser.parse("\x1b]0;title\x07a\x1b[1;38;5;196mb\x1b[22;48;2;1;2;3mc\x1b[2Kd\x1b[0me\x1b[31m");
+        let styled = |text: &str, style: Style| StyledText { text: text.into(), style };
+        assert_eq!(
+            pieces,
+            vec![
+                styled("a", Style::default()),
+                styled(
+                    "b",
+                    Style {
+                        foreground: Some(Color::Indexed { index: 196 }),
+                        attributes: vec![Attribute::Bold],
+                        ..Default::default()
+                    }
+                ),
+                styled(
+                    "cd",
+                    Style {
+                        foreground: Some(Color::Indexed { index: 196 }),
+                        background: Some(Color::RGB { r: 1, g: 2, b: 3 }),
+                        ..Default::default()
+                    }
+                ),
+                styled("e", Style::default()),
+            ]
+        );
+
+        // 样式延续到下一次解析，直到被重置
+        let red = Style { foreground: Some(Color::Indexed { index: 1 }), ..Default::default() };
+        assert_eq!(parser.parse("f\x1b[mg"), vec![styled("f", red), styled("g", Style::default())]);
+
+        // 冒号分隔的子参数属于同一个参数，色彩空间编号被跳过
+        let mut parser = AnsiParser::default();
+        let pieces = parser.parse("\x1b[38:2::255:0:0ma\x1b[38:5:196;4:3mb\x1b[4:0;48:2:0:1:2:3mc");
+        assert_eq!(
+            pieces,
+            vec![
+                styled("a", Style { foreground: Some(Color::RGB { r: 255, g: 0, b: 0 }), ..Default::default() }),
+                styled(
+                    "b",
+                    Style {
+                        foreground: Some(Color::Indexed { index: 196 }),
+                        attributes: vec![Attribute::Underline],
+                        ..Default::default()
+                    }
+                ),
+                styled(
+                    "c",
+                    Style {
+                        foreground: Some(Color::Indexed { index: 196 }),
+                        background: Some(Color::RGB { r: 1, g: 2, b: 3 }),
+                        ..Default::default()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn svg_output_lays_out_a_grid() {
+        use crate::format::{OutputFormat, SvgOptions};
+
+        let rules = vec![
+            Rule {
+                keyword: "<err>".into(),
+                color: Some(Color::Preset { name: "Red".into() }),
+                style: Style { attributes: vec![Attribute::Bold], ..Default::default() },
+                ..Default::default()
+            },
+            Rule {
+                keyword: "gone".into(),
+                style: Style { attributes: vec![Attribute::Underline, Attribute::Strikethrough], ..Default::default() },
+                ..Default::default()
+            },
+        ];
+        let options = SvgOptions {
+            font_size: 10,
+            background: Some(Color::RGB { r: 0, g: 0, b: 0 }),
+            columns: Some(12),
+            ..Default::default()
+        };
+        let options = EngineOptions { output: OutputFormat::Svg(options), ..Default::default() };
+        let engine = HighlightingEngine::with_rule_set(&RuleSet::from(rules), &options).unwrap();
+        assert!(engine.buffers_output());
+
+        let mut out = Vec::new();
+        super::process_stream("a\tb <err> &\ngone\n".as_bytes(), &engine, None, &mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+
+        // 制表符展开到第 8 列，超过 12 列的部分换行
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"92\" height=\"62\""));
+        assert!(svg.contains("<rect width=\"100%\" height=\"100%\" rx=\"5\" fill=\"#000000\"/>"));
+        assert!(svg.contains(
+            "<text y=\"20.5\"><tspan x=\"10\" fill=\"#d4d4d4\">a       b </tspan>\
+             <tspan x=\"70\" fill=\"#cd0000\" font-weight=\"bold\">&lt;e</tspan></text>"
+        ));
+        assert!(svg.contains(
+            "<text y=\"34.5\"><tspan x=\"10\" fill=\"#cd0000\" font-weight=\"bold\">rr&gt;</tspan>\
+             <tspan x=\"28\" fill=\"#d4d4d4\"> &amp;</tspan></text>"
+        ));
+        // 下划线和删除线合并为一个属性
+        assert!(svg.contains(
+            "<text y=\"48.5\"><tspan x=\"10\" fill=\"#d4d4d4\" text-decoration=\"underline line-through\">gone</tspan></text>"
+        ));
+        assert!(svg.ends_with("</svg>\n"));
+    }
+
+    #[test]
+    fn input_escapes_lie_beneath_rule_styles() {
+        use crate::format::{HtmlOptions, OutputFormat};
+
+        let rules = vec![Rule {
+            keyword: "error".into(),
+            color: Some(Color::Preset { name: "Red".into() }),
+            ..Default::default()
+        }];
+        let render = |output: OutputFormat| {
+            let options = EngineOptions { output, ..Default::default() };
+            let engine = HighlightingEngine::with_rule_set(&RuleSet::from(rules.clone()), &options).unwrap();
+            let mut out = Vec::new();
+            // 样式跨行延续，规则只匹配可见文本
+            let input = "\x1b[1;32mok\x1b[4m err\nor\x1b[0m <error>\n";
+            super::process_stream(input.as_bytes(), &engine, None, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            render(OutputFormat::Html(HtmlOptions { classes: true, standalone: false })),
+            "<span class=\"hl-bold\" style=\"color: #00cd00\">ok</span>\
+             <span class=\"hl-bold hl-underline\" style=\"color: #00cd00\"> err</span>\n\
+             <span class=\"hl-bold hl-underline\" style=\"color: #00cd00\">or</span> \
+             &lt;<span class=\"hl-fg-cd0000\">error</span>&gt;\n"
+        );
+
+        let json = render(OutputFormat::JsonLines);
+        let records: Vec<serde_json::Value> = json.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records[0]["text"], "ok err");
+        assert_eq!(records[1]["text"], "or <error>");
+        assert_eq!(
+            records[1]["spans"],
+            serde_json::json!([
+                { "start": 0, "end": 2, "style": { "foreground": { "index": 2 }, "attributes": ["bold", "underline"] } },
+                { "start": 4, "end": 9, "rule": 0, "style": { "foreground": "Red" } },
+            ])
+        );
+    }
+
+    #[test]
+    fn ansi_modes_match_visible_text() {
+        use crate::ansi::AnsiMode;
+
+        let rules = vec![Rule {
+            keyword: "error".into(),
+            color: Some(Color::Preset { name: "Red".into() }),
+            ..Default::default()
+        }];
+        let render = |ansi: Option<AnsiMode>| {
+            let options = EngineOptions { ansi, color_depth: ColorDepth::Ansi16, ..Default::default() };
+            let engine = HighlightingEngine::with_rule_set(&RuleSet::from(rules.clone()), &options).unwrap();
+            let mut out = Vec::new();
+            let input = "\x1b]8;;https://x\x07link\x1b]8;;\x07 \x1b[1mer\x1b[4mror\x1b[0m\x1b(B!\n";
+            super::process_stream(input.as_bytes(), &engine, None, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // 默认原样输出，规则无法匹配被转义序列隔开的文本；ESC ( B 这样的序列不会留下字符
+        assert!(!render(None).contains("\x1b[31m"));
+        assert_eq!(render(Some(AnsiMode::Strip)), "link \x1b[31merror\x1b[0m!\n");
+        assert_eq!(
+            render(Some(AnsiMode::Preserve)),
+            "\x1b]8;;https://x\x07link\x1b]8;;\x07 \x1b[1;31mer\x1b[0m\x1b[1;4;31mror\x1b(B\x1b[0m!\n"
+        );
+    }
 }
@@ -1,85 +1,171 @@
-use crate::rules::{Color, Rule};
+use crate::rules::{Context, Rule, RuleSet};
 use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
 
 /// Built-in C++ syntax highlighting preset.
-pub(super) static CPP: Lazy<Vec<Rule>> = Lazy::new(|| {
+///
+/// String literals and block comments are contexts, so keywords inside
+/// them are not highlighted.
+pub(super) static CPP: Lazy<RuleSet> = Lazy::new(|| RuleSet {
+    rules: rules(),
+    contexts: BTreeMap::from([
+        ("string".to_string(), Context {
+            kind: Some("string".into()),
+            rules: vec![
+                Rule {
+                    keyword: r"\\(x[0-9a-fA-F]+|[0-7]{1,3}|.)".into(),
+                    is_regex: true,
+                    kind: Some("constant.escape".into()),
+                    ..Default::default()
+                },
+                Rule {
+                    keyword: "\"".into(),
+                    pop: true,
+                    ..Default::default()
+                },
+                // 未闭合的字符串在行尾结束
+                Rule {
+                    keyword: "$".into(),
+                    is_regex: true,
+                    pop: true,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }),
+        ("comment".to_string(), Context {
+            kind: Some("comment".into()),
+            rules: vec![Rule {
+                keyword: r"\*/".into(),
+                is_regex: true,
+                pop: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }),
+    ]),
+});
+
+fn rules() -> Vec<Rule> {
     vec![
         // 1. 字符串
         Rule {
-            keyword: r#""[^"\\]*(\\.[^"\\]*)*"|'[^'\\]*(\\.[^'\\]*)*'"#.into(),
+            keyword: r#"R"([^(\s]*)\("#.into(),
+            end: Some(r#"\)\1""#.into()),
             is_regex: true,
             ignore_case: false,
-            color: Color::RGB { r: 206, g: 145, b: 120 },
+            kind: Some("string".into()),
+            ..Default::default()
+        },
+        Rule {
+            keyword: "\"".into(),
+            push: Some("string".into()),
+            kind: Some("string".into()),
+            ..Default::default()
+        },
+        Rule {
+            keyword: r"'([^'\\]|\\.)*'".into(),
+            is_regex: true,
+            ignore_case: false,
+            kind: Some("string".into()),
+            ..Default::default()
         },
         // 2. 注释
         Rule {
-            keyword: r"//.*|/\*.*\*/".into(),
+            keyword: r"//.*".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::RGB { r: 106, g: 153, b: 85 },
+            kind: Some("comment".into()),
+            ..Default::default()
+        },
+        Rule {
+            keyword: r"/\*".into(),
+            is_regex: true,
+            ignore_case: false,
+            kind: Some("comment".into()),
+            push: Some("comment".into()),
+            ..Default::default()
         },
         // 3. 预处理宏
         Rule {
             keyword: r"^\s*#\s*(include|define|ifdef|ifndef|endif|if|else|pragma|line|error).*$".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::Preset { name: "Magenta".into() },
+            kind: Some("preprocessor".into()),
+            ..Default::default()
         },
         // 4. 数字
         Rule {
             keyword: r"\b(0x[0-9a-fA-F]+|0b[01]+|\d+\.?\d*([eE][+-]?\d+)?|\d+)\b".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::RGB { r: 181, g: 206, b: 168 },
+            kind: Some("number".into()),
+            ..Default::default()
         },
         // 5. 符号全集
         Rule {
             keyword: r"(->|::|<<=|>>=|==|!=|<=|>=|&&|\|\||\+\+|--|<<|>>|[\+\-\*\/%=&<>!&\|\^~\.\?:;])".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::Preset { name: "Red".into() },
+            kind: Some("operator".into()),
+            ..Default::default()
         },
         // 6. 括号
         Rule {
             keyword: r"[\(\)\{\}\[\]]".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::RGB { r: 255, g: 215, b: 0 },
+            kind: Some("punctuation".into()),
+            ..Default::default()
         },
         // 7. 控制流关键字
         Rule {
             keyword: r"\b(if|else|for|while|do|switch|case|default|return|break|continue|goto|throw|try|catch)\b".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::RGB { r: 197, g: 134, b: 192 },
+            kind: Some("keyword.control".into()),
+            ..Default::default()
         },
         // 8. 类型与限定符
         Rule {
             keyword: r"\b(int|long|short|char|float|double|bool|void|size_t|u?int(8|16|32|64)_t|auto|unsigned|signed|const|static|inline|virtual|override|final|volatile|mutable|thread_local|explicit|enum|struct|class|union|typename|template)\b".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::Preset { name: "Blue".into() },
+            kind: Some("type".into()),
+            ..Default::default()
         },
         // 9. 其他核心关键字
         Rule {
             keyword: r"\b(public|private|protected|using|namespace|friend|this|operator|new|delete|true|false|nullptr|constexpr|static_cast|dynamic_cast|reinterpret_cast|const_cast)\b".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::Preset { name: "Cyan".into() },
+            kind: Some("keyword".into()),
+            ..Default::default()
         },
         // 10. std 命名空间
         Rule {
             keyword: r"\bstd::\w*".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::Preset { name: "Yellow".into() },
+            kind: Some("namespace".into()),
+            ..Default::default()
         },
         // 11. PascalCase 类名
         Rule {
             keyword: r"\b[A-Z]\w*\b".into(),
             is_regex: true,
             ignore_case: false,
-            color: Color::Preset { name: "Green".into() },
+            kind: Some("type.class".into()),
+            ..Default::default()
+        },
+        // 12. 注释中的待办标记（叠加层，可在注释内部高亮）
+        Rule {
+            keyword: r"\b(TODO|FIXME|XXX|HACK)\b".into(),
+            is_regex: true,
+            ignore_case: false,
+            kind: Some("todo".into()),
+            layer: 1,
+            ..Default::default()
         },
     ]
-});
+}
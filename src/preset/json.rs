@@ -1,4 +1,4 @@
-use crate::rules::{Color, Rule};
+use crate::rules::Rule;
 use once_cell::sync::Lazy;
 use std::convert::Into;
 
@@ -9,27 +9,31 @@ pub(super) static JSON: Lazy<Vec<Rule>> = Lazy::new(|| vec![
         keyword: r#""[^"]+"\s*:"#.to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 214, g: 157, b: 133 }, // purple-ish
+        kind: Some("key".into()),
+        ..Default::default()
     },
     // ===== Strings =====
     Rule {
         keyword: r#""([^"\\]|\\.)*""#.to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 181, g: 206, b: 168 }, // green-ish
+        kind: Some("string".into()),
+        ..Default::default()
     },
     // ===== Numbers =====
     Rule {
         keyword: r"\b\d+(\.\d+)?\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 206, g: 145, b: 120 },
+        kind: Some("number".into()),
+        ..Default::default()
     },
     // ===== Booleans / null =====
     Rule {
         keyword: r"\b(true|false|null)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::Preset { name: "Cyan".into() },
+        kind: Some("constant".into()),
+        ..Default::default()
     },
 ]);
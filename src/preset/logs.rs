@@ -1,192 +1,273 @@
-use crate::rules::{Color, Rule};
+use crate::rules::{CaptureStyle, Rule};
 use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
 use std::convert::Into;
 
 pub(super) static LOGS: Lazy<Vec<Rule>> = Lazy::new(|| vec![
     // 1. ===== Timestamps =====
     // 最优先匹配，防止日期中的数字和横杠被后续规则拆散
     Rule {
+        id: Some("timestamp".into()),
+        tags: vec!["time".into()],
         keyword: r"\b\d{4}-\d{2}-\d{2}[ T]\d{2}:\d{2}:\d{2}(\.\d+)?\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 180, g: 180, b: 180 },
+        kind: Some("timestamp".into()),
+        ..Default::default()
     },
 
     // 2. ===== IP Addresses =====
     // 必须在普通数字之前，否则 192.168... 会被匹配成 4 个数字
     Rule {
+        id: Some("ipv4".into()),
+        tags: vec!["network".into()],
         keyword: r"\b\d{1,3}(\.\d{1,3}){3}\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 255, g: 165, b: 0 },
+        kind: Some("address".into()),
+        ..Default::default()
     },
     Rule {
+        id: Some("ipv6".into()),
+        tags: vec!["network".into()],
         keyword: r"\b([0-9a-fA-F]{0,4}:){1,7}[0-9a-fA-F]{0,4}\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 255, g: 165, b: 0 },
+        kind: Some("address".into()),
+        ..Default::default()
     },
 
     // 3. ===== URLs / Domains =====
     Rule {
+        id: Some("url".into()),
+        tags: vec!["network".into()],
         keyword: r"https?://[^\s/$.?#].[^\s]*".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 80, g: 200, b: 250 },
+        kind: Some("url".into()),
+        ..Default::default()
     },
     Rule {
+        id: Some("domain".into()),
+        tags: vec!["network".into()],
         keyword: r"\b([a-zA-Z0-9-]+\.)+[a-zA-Z]{2,}\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 100, g: 150, b: 200 },
+        kind: Some("domain".into()),
+        ..Default::default()
     },
 
     // 4. ===== JSON keys =====
     // 带有冒号的键，优先级高于普通引号字符串
     Rule {
+        id: Some("json-key".into()),
+        tags: vec!["json".into()],
         keyword: r#""[^"]+"\s*:"#.to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 200, g: 100, b: 200 },
+        kind: Some("key".into()),
+        ..Default::default()
     },
 
     // 5. ===== Common fields (key=value) =====
+    // 键、等号和值分别着色
     Rule {
-        keyword: r"\b(user|uid|id|request_id|trace_id|span_id)=\S+\b".to_string(),
+        id: Some("field".into()),
+        tags: vec!["fields".into()],
+        keyword: r"\b(user|uid|id|request_id|trace_id|span_id)=(\S+)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::RGB { r: 206, g: 145, b: 120 },
+        kind: Some("field".into()),
+        captures: BTreeMap::from([
+            ("1".into(), CaptureStyle { kind: Some("field.name".into()), ..Default::default() }),
+            ("2".into(), CaptureStyle { kind: Some("field.value".into()), ..Default::default() }),
+        ]),
+        ..Default::default()
     },
 
     // 6. ===== Source / Module =====
     // 如 com.package.Class
     Rule {
+        id: Some("module".into()),
+        tags: vec!["source".into()],
         keyword: r"\b([A-Za-z_][\w$]*\.)+[A-Za-z_][\w$]*\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 86, g: 156, b: 214 },
+        kind: Some("module".into()),
+        ..Default::default()
     },
 
     // 7. ===== File paths =====
     Rule {
+        id: Some("path".into()),
+        tags: vec!["source".into()],
         keyword: r"(/[^ \t\n]+)+".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 152, g: 195, b: 121 },
+        kind: Some("path".into()),
+        ..Default::default()
     },
 
     // 8. ===== Log levels =====
     Rule {
+        id: Some("level-fatal".into()),
+        tags: vec!["level".into()],
         keyword: r"\b(FATAL|CRITICAL|FF)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::RGB { r: 255, g: 0, b: 0 },
+        kind: Some("level.fatal".into()),
+        ..Default::default()
     },
     Rule {
+        id: Some("level-error".into()),
+        tags: vec!["level".into()],
         keyword: r"\b(ERROR|EE)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::Preset { name: "Red".into() },
+        kind: Some("level.error".into()),
+        ..Default::default()
     },
     Rule {
+        id: Some("level-warn".into()),
+        tags: vec!["level".into()],
         keyword: r"\b(WARN(ING)?|WW)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::Preset { name: "Yellow".into() },
+        kind: Some("level.warn".into()),
+        ..Default::default()
     },
     Rule {
         // 适配 INFO, II
+        id: Some("level-info".into()),
+        tags: vec!["level".into()],
         keyword: r"\b(INFO|II)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::Preset { name: "Green".into() },
+        kind: Some("level.info".into()),
+        ..Default::default()
     },
     Rule {
         // 适配 DEBUG, DD
+        id: Some("level-debug".into()),
+        tags: vec!["level".into()],
         keyword: r"\b(DEBUG|DD)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::Preset { name: "Cyan".into() },
+        kind: Some("level.debug".into()),
+        ..Default::default()
     },
     Rule {
         // 适配 TRACE, VV (Verbose)
+        id: Some("level-trace".into()),
+        tags: vec!["level".into()],
         keyword: r"\b(TRACE|VV)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::RGB { r: 160, g: 160, b: 160 },
+        kind: Some("level.trace".into()),
+        ..Default::default()
     },
 
     // 9. ===== HTTP Methods / Status =====
     Rule {
+        id: Some("http-method".into()),
+        tags: vec!["http".into()],
         keyword: r"\b(GET|POST|PUT|DELETE|PATCH|OPTIONS|HEAD)\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 0, g: 200, b: 0 },
+        kind: Some("http.method".into()),
+        ..Default::default()
     },
     Rule {
+        id: Some("http-status".into()),
+        tags: vec!["http".into()],
         keyword: r"\b(1\d{2}|2\d{2}|3\d{2}|4\d{2}|5\d{2})\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 255, g: 140, b: 0 },
+        kind: Some("http.status".into()),
+        ..Default::default()
     },
 
     // 10. ===== Thread / PID =====
     Rule {
+        id: Some("thread".into()),
+        tags: vec!["process".into()],
         keyword: r"\[(main|worker-\d+|thread-\d+)\]".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::RGB { r: 140, g: 140, b: 255 },
+        kind: Some("process.thread".into()),
+        ..Default::default()
     },
     Rule {
+        id: Some("pid".into()),
+        tags: vec!["process".into()],
         keyword: r"\bpid=\d+\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 140, g: 140, b: 255 },
+        kind: Some("process.pid".into()),
+        ..Default::default()
     },
 
     // 11. ===== Exceptions / Stacktrace =====
     Rule {
+        id: Some("exception".into()),
+        tags: vec!["exception".into()],
         keyword: r"\b(Exception|Error|Traceback)\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 255, g: 50, b: 50 },
+        kind: Some("exception".into()),
+        ..Default::default()
     },
     Rule {
+        id: Some("stack-frame".into()),
+        tags: vec!["exception".into()],
         keyword: r"^\s+at\s+[^\s]+\([^\)]*\)".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 180, g: 180, b: 255 },
+        kind: Some("exception.frame".into()),
+        ..Default::default()
     },
 
     // 12. ===== SQL / Shell commands =====
     Rule {
+        id: Some("sql".into()),
+        tags: vec!["sql".into()],
         keyword: r"\b(SELECT|INSERT|UPDATE|DELETE|FROM|WHERE|JOIN|CREATE|DROP|ALTER)\b".to_string(),
         is_regex: true,
         ignore_case: true,
-        color: Color::RGB { r: 0, g: 255, b: 200 },
+        kind: Some("sql".into()),
+        ..Default::default()
     },
     Rule {
+        id: Some("shell-variable".into()),
+        tags: vec!["shell".into()],
         keyword: r"(\$[a-zA-Z_][\w]*)".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 255, g: 200, b: 100 },
+        kind: Some("variable".into()),
+        ..Default::default()
     },
 
     // 13. ===== Numbers =====
     // 放在倒数第二，作为剩余数字的保底匹配
     Rule {
+        id: Some("number".into()),
+        tags: vec!["literal".into()],
         keyword: r"\b\d+(\.\d+)?\b".to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 181, g: 206, b: 168 },
+        kind: Some("number".into()),
+        ..Default::default()
     },
 
     // 14. ===== Quoted strings =====
     // 放在最后，防止它吞掉 JSON key 或其他特定格式
     Rule {
+        id: Some("string".into()),
+        tags: vec!["literal".into()],
         keyword: r#""([^"\\]|\\.)*""#.to_string(),
         is_regex: true,
         ignore_case: false,
-        color: Color::RGB { r: 214, g: 157, b: 133 },
+        kind: Some("string".into()),
+        ..Default::default()
     },
 ]);
\ No newline at end of file
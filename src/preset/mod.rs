@@ -2,15 +2,106 @@ mod logs;
 mod json;
 mod cpp;
 
-use crate::rules::Rule;
+use crate::rules::RuleSet;
 use anyhow::Result;
+use std::path::PathBuf;
+
+/// 内置预设的名称和说明
+const BUILTIN_PRESETS: &[(&str, &str)] = &[
+    ("logs", "Application and system logs: timestamps, levels, addresses, paths, stack traces"),
+    ("json", "JSON documents and JSON lines"),
+    ("cpp", "C and C++ source code"),
+];
+
+/// 可用预设的信息，用于 `list-presets`
+#[derive(Debug, Clone)]
+pub struct PresetInfo {
+    pub name: String,
+    pub description: Option<String>,
+    /// 用户预设的文件路径，内置预设为 `None`
+    pub path: Option<PathBuf>,
+}
+
+fn is_builtin(name: &str) -> bool {
+    BUILTIN_PRESETS.iter().any(|(builtin, _)| builtin.eq_ignore_ascii_case(name))
+}
 
 /// 获取指定名称的预设规则
-pub fn get_preset(name: &str) -> Result<Vec<Rule>> {
+pub fn get_preset(name: &str) -> Result<RuleSet> {
     match name.to_ascii_lowercase().as_str() {
-        "logs" => Ok(logs::LOGS.to_vec()),
-        "json" => Ok(json::JSON.to_vec()),
-        "cpp" => Ok(cpp::CPP.to_vec()),
+        "logs" => Ok(logs::LOGS.to_vec().into()),
+        "json" => Ok(json::JSON.to_vec().into()),
+        "cpp" => Ok(cpp::CPP.clone()),
         _ => anyhow::bail!("Unknown preset '{}'", name),
     }
 }
+
+/// 按名称查找预设：内置预设优先，其次依次在 `search_dirs` 中查找 `<name>.yaml`
+pub fn find_preset(name: &str, search_dirs: &[PathBuf]) -> Result<RuleSet> {
+    if is_builtin(name) {
+        return get_preset(name);
+    }
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        anyhow::bail!("Invalid preset name '{}'", name);
+    }
+
+    for dir in search_dirs {
+        let path = dir.join(format!("{}.yaml", name));
+        if path.is_file() {
+            return crate::arg_parser::load_rule_set_from_file(&path)
+                .map_err(|e| anyhow::anyhow!("{} (in preset {})", e, path.display()));
+        }
+    }
+
+    let searched: Vec<String> = search_dirs.iter().map(|dir| dir.display().to_string()).collect();
+    anyhow::bail!(
+        "Unknown preset '{}': not a built-in preset ({}) or a file in {}",
+        name,
+        BUILTIN_PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", "),
+        if searched.is_empty() { "any preset directory".to_string() } else { searched.join(", ") }
+    )
+}
+
+/// 列出所有可用的预设：先是内置预设，然后是 `search_dirs` 中的用户预设。
+/// 被内置预设或更靠前的目录遮蔽的同名文件不会列出
+pub fn list_presets(search_dirs: &[PathBuf]) -> Vec<PresetInfo> {
+    let mut presets: Vec<PresetInfo> = BUILTIN_PRESETS
+        .iter()
+        .map(|(name, description)| PresetInfo {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            path: None,
+        })
+        .collect();
+
+    for dir in search_dirs {
+        // 目录不存在或无法读取时跳过
+        let Ok(entries) = std::fs::read_dir(dir) else { continue };
+        let mut user_presets: Vec<PresetInfo> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "yaml"))
+            .filter_map(|path| {
+                let name = path.file_stem()?.to_str()?.to_string();
+                if name.starts_with('.') || is_builtin(&name) || presets.iter().any(|p| p.name == name) {
+                    return None;
+                }
+                let description = match crate::arg_parser::read_description(&path) {
+                    Ok(description) => description,
+                    Err(e) => Some(format!("(invalid: {})", e)),
+                };
+                Some(PresetInfo { name, description, path: Some(path) })
+            })
+            .collect();
+        user_presets.sort_by(|a, b| a.name.cmp(&b.name));
+        presets.extend(user_presets);
+    }
+    presets
+}
+
+/// 返回内置预设的说明
+pub fn builtin_description(name: &str) -> Option<&'static str> {
+    BUILTIN_PRESETS
+        .iter()
+        .find(|(builtin, _)| builtin.eq_ignore_ascii_case(name))
+        .map(|(_, description)| *description)
+}
@@ -2,7 +2,7 @@ macro_rules! define_preset_colors {
     (
         $(
             $Name:ident => {
-                ansi: $ansi:expr,
+                index: $index:expr,
                 aliases: [$($alias:expr),+ $(,)?]
             }
         ),+ $(,)?
@@ -13,19 +13,21 @@ macro_rules! define_preset_colors {
         }
 
         impl PresetColor {
-            pub(crate) fn to_ansi(self) -> &'static str {
+            /// Returns the index of this color among the 16 basic colors.
+            pub(crate) fn index(self) -> u8 {
                 match self {
-                    $(PresetColor::$Name => $ansi),+
+                    $(PresetColor::$Name => $index),+
                 }
             }
 
-            pub(crate) fn parse(name: &str) -> anyhow::Result<Self> {
-                let name = name.to_ascii_lowercase();
-                match name.as_str() {
+            /// Looks up a preset color by a normalized name
+            /// (lowercase, without `_`, `-` or spaces).
+            pub(crate) fn from_name(name: &str) -> Option<Self> {
+                match name {
                     $(
-                        $($alias)|+ => Ok(PresetColor::$Name),
+                        $($alias)|+ => Some(PresetColor::$Name),
                     )+
-                    _ => anyhow::bail!("Unknown preset color: {}", name),
+                    _ => None,
                 }
             }
         }
@@ -33,38 +35,82 @@ macro_rules! define_preset_colors {
 }
 
 define_preset_colors! {
+    Black => {
+        index: 0,
+        aliases: ["black"]
+    },
     Red => {
-        ansi: "\x1b[31m",
+        index: 1,
         aliases: ["red"]
     },
+    Green => {
+        index: 2,
+        aliases: ["green"]
+    },
     Yellow => {
-        ansi: "\x1b[33m",
+        index: 3,
         aliases: ["yellow", "yel"]
     },
     Blue => {
-        ansi: "\x1b[34m",
+        index: 4,
         aliases: ["blue"]
     },
-    Green => {
-        ansi: "\x1b[32m",
-        aliases: ["green"]
+    Magenta => {
+        index: 5,
+        aliases: ["magenta", "purple"]
     },
     Cyan => {
-        ansi: "\x1b[36m",
+        index: 6,
         aliases: ["cyan"]
     },
-    Magenta => {
-        ansi: "\x1b[35m",
-        aliases: ["magenta", "purple"]
+    White => {
+        index: 7,
+        aliases: ["white"]
+    },
+    BrightBlack => {
+        index: 8,
+        aliases: ["brightblack", "gray", "grey"]
+    },
+    BrightRed => {
+        index: 9,
+        aliases: ["brightred"]
+    },
+    BrightGreen => {
+        index: 10,
+        aliases: ["brightgreen"]
+    },
+    BrightYellow => {
+        index: 11,
+        aliases: ["brightyellow"]
+    },
+    BrightBlue => {
+        index: 12,
+        aliases: ["brightblue"]
+    },
+    BrightMagenta => {
+        index: 13,
+        aliases: ["brightmagenta", "brightpurple"]
+    },
+    BrightCyan => {
+        index: 14,
+        aliases: ["brightcyan"]
+    },
+    BrightWhite => {
+        index: 15,
+        aliases: ["brightwhite"]
     },
 }
 
+mod css;
 
-use serde::Deserialize;
+use crate::term::ColorDepth;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
 
 /// A single highlighting rule.
 ///
-/// Each rule defines a keyword or pattern to match, along with the color
+/// Each rule defines a keyword or pattern to match, along with the style
 /// used to render matched text.
 ///
 /// # Case sensitivity
@@ -77,6 +123,62 @@ use serde::Deserialize;
 /// - If the CLI flag `--ignore-case` is provided, it overrides this
 ///   setting and forces all rules to be case-insensitive.
 ///
+/// # Styling
+///
+/// `color` and `background` are shorthands for `style.foreground` and
+/// `style.background`. When both forms are given, the `style` block takes
+/// precedence.
+///
+/// A rule can instead (or additionally) name a semantic `kind` such as
+/// `comment` or `level.error`, which the active theme maps to a style.
+/// Colors and attributes set on the rule itself are layered on top of the
+/// theme's style.
+///
+/// # Capture groups
+///
+/// A regex rule can style individual capture groups, referenced by index
+/// or by name, through `captures`. Each capture style is layered on top of
+/// the rule's style; parts of the match outside any styled group keep the
+/// rule's style. If styled groups overlap, the one starting first wins.
+///
+/// # Layers
+///
+/// Rules on the default layer `0` compete for text: the first rule to
+/// match claims it. Rules with a higher `layer` are overlays that are
+/// matched independently and may highlight text inside spans already
+/// claimed by lower layers; the styles are then composed, with the higher
+/// layer's colors taking precedence and attributes combined.
+///
+/// # Regions
+///
+/// A rule with an `end` pattern is a region: once `keyword` (which may
+/// also be written as `begin`) matches, everything up to and including
+/// the next match of `end` is styled as a whole, even when that is
+/// several lines further down. For regex rules, `end` may refer to the
+/// text of the begin pattern's capture groups as `\1` … `\9`, which
+/// matches heredocs whose terminator is chosen on the opening line.
+///
+/// # Identifiers and tags
+///
+/// A rule can have an `id`, which must be unique within a rule set, and
+/// any number of `tags`. Both let a run select rules without editing the
+/// configuration: `--disable-rule <id>`, `--enable-tag <tag>`,
+/// `--disable-tag <tag>` and `--filter` accept them.
+///
+/// # Line scope
+///
+/// With `scope: line`, a match anywhere in a line styles the whole line
+/// instead of just the matched text. The line style lies beneath all
+/// other highlights, which are layered on top of it. If several line
+/// rules match, the first one wins.
+///
+/// # Contexts
+///
+/// A rule on layer `0` can `push` a named [`Context`] when it matches,
+/// after which only that context's rules are matched until one of them
+/// `pop`s it again. The stack of contexts carries over from one line to
+/// the next. See [`RuleSet`].
+///
 /// # YAML
 /// Rules are typically loaded from a YAML configuration file.
 ///
@@ -84,64 +186,690 @@ use serde::Deserialize;
 ///
 /// ```yaml
 /// rules:
-///   - keyword: "ERROR"
+///   - id: error
+///     tags: [level]
+///     keyword: "ERROR"
 ///     color: { name: "Red" }
 ///     is_regex: false
+///   - keyword: "CRITICAL"
+///     color: { name: "Yellow" }
+///     background: { r: 200, g: 0, b: 0 }
+///   - keyword: "FATAL"
+///     style:
+///       foreground: { name: "Red" }
+///       attributes: [bold, underline]
 ///   - keyword: "//.*|/\\*.*\\*/"
 ///     is_regex: true
 ///     ignore_case: false
-///     color: { r: 106, g: 153, b: 85 }
+///     kind: comment
+///   - keyword: '\b(user|uid)=(?P<value>\S+)'
+///     is_regex: true
+///     color: { name: "Cyan" }
+///     captures:
+///       1: { color: { name: "Blue" } }
+///       value: { kind: string }
+///   - keyword: "TODO"
+///     layer: 1
+///     style: { attributes: [bold] }
+///   - begin: '/\*'
+///     end: '\*/'
+///     is_regex: true
+///     kind: comment
+///   - keyword: "DEBUG"
+///     scope: line
+///     style: { attributes: [dim] }
 /// ```
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Rule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(alias = "begin")]
     pub keyword: String,
-    pub color: Color,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push: Option<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub pop: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<Color>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<Color>,
+    #[serde(default, skip_serializing_if = "Style::is_empty")]
+    pub style: Style,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub captures: BTreeMap<String, CaptureStyle>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub layer: u8,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub scope: Scope,
+    #[serde(default, skip_serializing_if = "is_default")]
     pub is_regex: bool,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_default")]
     pub ignore_case: bool,
 }
 
+impl Rule {
+    /// Returns the name used for this rule in messages: its `id` if it
+    /// has one, otherwise its keyword.
+    pub(crate) fn label(&self) -> &str {
+        self.id.as_deref().unwrap_or(&self.keyword)
+    }
+
+    /// Returns `true` if the `--filter` selector `selector` chooses this
+    /// rule: it equals the rule's id or one of its tags, or the rule's kind
+    /// equals it or is a child of it (`level` selects `level.error`).
+    pub(crate) fn is_selected_by(&self, selector: &str) -> bool {
+        self.id.as_deref() == Some(selector)
+            || self.tags.iter().any(|tag| tag == selector)
+            || self.kind.as_deref().is_some_and(|kind| {
+                kind.strip_prefix(selector).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            })
+    }
+
+    /// Returns the style this rule renders with, with the `color` and
+    /// `background` shorthands folded in.
+    pub(crate) fn effective_style(&self) -> Style {
+        fold_shorthands(&self.style, &self.color, &self.background)
+    }
+
+    /// Returns mutable references to every color this rule sets,
+    /// including those of its capture styles.
+    pub(crate) fn colors_mut(&mut self) -> impl Iterator<Item = &mut Color> {
+        let own = [
+            self.color.as_mut(),
+            self.background.as_mut(),
+            self.style.foreground.as_mut(),
+            self.style.background.as_mut(),
+        ];
+        let captures = self.captures.values_mut().flat_map(|c| {
+            [
+                c.color.as_mut(),
+                c.background.as_mut(),
+                c.style.foreground.as_mut(),
+                c.style.background.as_mut(),
+            ]
+        });
+        own.into_iter().chain(captures).flatten()
+    }
+}
+
+/// The part of a line a [`Rule`] styles when it matches.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Only the matched text.
+    #[default]
+    Match,
+    /// The whole line, beneath all other highlights.
+    Line,
+}
+
+/// The style of a single capture group of a regex [`Rule`].
+///
+/// Accepts the same styling fields as a rule.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CaptureStyle {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<Color>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<Color>,
+    #[serde(default, skip_serializing_if = "Style::is_empty")]
+    pub style: Style,
+}
+
+impl CaptureStyle {
+    /// Returns the style of this capture, with the `color` and
+    /// `background` shorthands folded in.
+    pub(crate) fn effective_style(&self) -> Style {
+        fold_shorthands(&self.style, &self.color, &self.background)
+    }
+}
+
+/// A named set of rules that is active while it is on top of the
+/// context stack.
+///
+/// Text inside the context that none of its rules match is rendered in
+/// the context's own style, and the styles of its rules are layered on
+/// top of it.
+///
+/// # Examples
+///
+/// ```yaml
+/// contexts:
+///   string:
+///     kind: string
+///     rules:
+///       - keyword: '\\.'
+///         is_regex: true
+///         kind: constant
+///       - keyword: '"'
+///         pop: true
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Context {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<Color>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<Color>,
+    #[serde(default, skip_serializing_if = "Style::is_empty")]
+    pub style: Style,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+}
+
+impl Context {
+    /// Returns the style of text inside this context, with the `color`
+    /// and `background` shorthands folded in.
+    pub(crate) fn effective_style(&self) -> Style {
+        fold_shorthands(&self.style, &self.color, &self.background)
+    }
+
+    /// Returns mutable references to the colors of the context's own
+    /// style. The colors of its rules are not included.
+    pub(crate) fn colors_mut(&mut self) -> impl Iterator<Item = &mut Color> {
+        [
+            self.color.as_mut(),
+            self.background.as_mut(),
+            self.style.foreground.as_mut(),
+            self.style.background.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// A complete set of highlighting rules: the top-level rules and the named
+/// contexts they can push.
+///
+/// The top-level rules form the `main` context, which is active when the
+/// context stack is empty and can also be pushed by name.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub contexts: BTreeMap<String, Context>,
+}
+
+impl RuleSet {
+    /// Returns all rules of the set: the top-level rules followed by the
+    /// rules of each context.
+    pub fn iter(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter().chain(self.contexts.values().flat_map(|context| &context.rules))
+    }
+}
+
+impl From<Vec<Rule>> for RuleSet {
+    fn from(rules: Vec<Rule>) -> Self {
+        RuleSet { rules, contexts: BTreeMap::new() }
+    }
+}
+
+/// Returns `true` if `value` is the default value of its type, to skip it
+/// when serializing.
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Folds the `color` and `background` shorthands into `style`, keeping
+/// the colors already set in `style`.
+fn fold_shorthands(style: &Style, color: &Option<Color>, background: &Option<Color>) -> Style {
+    let mut style = style.clone();
+    if style.foreground.is_none() {
+        style.foreground = color.clone();
+    }
+    if style.background.is_none() {
+        style.background = background.clone();
+    }
+    style
+}
+
+/// A text style: foreground, background and a set of SGR attributes.
+///
+/// All fields are optional; an empty style leaves the text untouched.
+///
+/// # Examples
+///
+/// ```yaml
+/// style:
+///   foreground: { name: White }
+///   background: { r: 200, g: 0, b: 0 }
+///   attributes: [bold, underline]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Style {
+    #[serde(default, alias = "fg", skip_serializing_if = "Option::is_none")]
+    pub foreground: Option<Color>,
+    #[serde(default, alias = "bg", skip_serializing_if = "Option::is_none")]
+    pub background: Option<Color>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attributes: Vec<Attribute>,
+}
+
+impl Style {
+    /// Returns `true` if the style sets nothing.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.foreground.is_none() && self.background.is_none() && self.attributes.is_empty()
+    }
+
+    /// Layers `over` on top of this style.
+    ///
+    /// Colors set in `over` replace those of `self`, and attributes of
+    /// both styles are combined.
+    pub(crate) fn merge(&self, over: &Style) -> Style {
+        let mut attributes = self.attributes.clone();
+        for attr in &over.attributes {
+            if !attributes.contains(attr) {
+                attributes.push(*attr);
+            }
+        }
+        Style {
+            foreground: over.foreground.clone().or_else(|| self.foreground.clone()),
+            background: over.background.clone().or_else(|| self.background.clone()),
+            attributes,
+        }
+    }
+
+    /// Converts this style into a single combined ANSI escape sequence,
+    /// e.g. `\x1b[1;4;31m`, with colors reduced to the given depth.
+    ///
+    /// Returns an empty string if the style sets nothing or `depth` is
+    /// [`ColorDepth::None`].
+    pub(crate) fn to_ansi(&self, depth: ColorDepth) -> anyhow::Result<String> {
+        if depth == ColorDepth::None {
+            return Ok(String::new());
+        }
+
+        let mut params: Vec<String> = self
+            .attributes
+            .iter()
+            .map(|attr| attr.sgr_code().to_string())
+            .collect();
+
+        if let Some(fg) = &self.foreground {
+            params.push(fg.sgr_params(false, depth)?);
+        }
+        if let Some(bg) = &self.background {
+            params.push(bg.sgr_params(true, depth)?);
+        }
+
+        if params.is_empty() {
+            return Ok(String::new());
+        }
+        Ok(format!("\x1b[{}m", params.join(";")))
+    }
+}
+
+/// A text attribute, rendered as an SGR parameter.
+///
+/// In YAML, attributes are written in lowercase:
+///
+/// ```yaml
+/// attributes: [bold, italic, underline, dim, reverse, strikethrough]
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Attribute {
+    Bold,
+    Dim,
+    Italic,
+    Underline,
+    #[serde(alias = "inverse")]
+    Reverse,
+    #[serde(alias = "strike")]
+    Strikethrough,
+}
+
+impl Attribute {
+    fn sgr_code(self) -> u8 {
+        match self {
+            Attribute::Bold => 1,
+            Attribute::Dim => 2,
+            Attribute::Italic => 3,
+            Attribute::Underline => 4,
+            Attribute::Reverse => 7,
+            Attribute::Strikethrough => 9,
+        }
+    }
+}
+
 
 /// A color specification for highlighted text.
 ///
-/// Colors can be specified either as a predefined ANSI color
-/// or as a 24-bit RGB value.
+/// Colors can be specified as a preset name, an xterm-256 palette index,
+/// or a 24-bit RGB value.
+///
+/// Preset names cover the 16 basic ANSI colors (`Black` … `White` and
+/// their `Bright*` variants) as well as the CSS / X11 named colors such as
+/// `Orange` or `SteelBlue`. Names are case-insensitive, and `_`, `-` and
+/// spaces are ignored, so `bright_black` and `BrightBlack` are equivalent.
 ///
 /// # Examples
 ///
 /// Using a preset ANSI color:
 ///
 /// ```yaml
-/// color: { name: Red }
+/// color: { name: BrightBlack }
+/// ```
+///
+/// Using an xterm-256 palette index:
+/// ```yaml
+/// color: { index: 208 }
 /// ```
 ///
 /// Using a 24-bit RGB value:
 /// ```yaml
 /// color: { r: 181, g: 206, b: 168 }
 /// ```
-#[derive(Debug, Clone, Deserialize)]
-#[serde(untagged)]
+///
+/// Colors can also be written as plain strings: a preset name, a hex
+/// value (`#rrggbb` or `#rgb`) or a CSS-style `rgb(r, g, b)`:
+/// ```yaml
+/// color: red
+/// color: "#6a9955"
+/// color: "rgb(106, 153, 85)"
+/// ```
+///
+/// Referencing an entry of the configuration's `palette`:
+/// ```yaml
+/// color: { ref: accent }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Color {
     Preset{name: String},
+    Indexed { index: u8 },
     RGB { r: u8, g: u8, b: u8 },
+    /// A reference to a named palette entry, resolved when the
+    /// configuration is loaded.
+    Ref { name: String },
+}
+
+/// The accepted YAML forms of a [`Color`].
+#[derive(Deserialize, Serialize)]
+#[serde(
+    untagged,
+    expecting = "invalid color, expected a name, \"#rrggbb\", \"rgb(r, g, b)\", { name }, { index }, { r, g, b } or { ref }"
+)]
+enum ColorRepr {
+    String(String),
+    Preset { name: String },
+    Indexed { index: u8 },
+    Rgb { r: u8, g: u8, b: u8 },
+    Ref {
+        #[serde(rename = "ref")]
+        name: String,
+    },
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match ColorRepr::deserialize(deserializer)? {
+            ColorRepr::String(s) => s.parse().map_err(serde::de::Error::custom),
+            ColorRepr::Preset { name } => Ok(Color::Preset { name }),
+            ColorRepr::Indexed { index } => Ok(Color::Indexed { index }),
+            ColorRepr::Rgb { r, g, b } => Ok(Color::RGB { r, g, b }),
+            ColorRepr::Ref { name } => Ok(Color::Ref { name }),
+        }
+    }
+}
+
+/// Colors are written in their shortest form: preset names and hex values
+/// as strings, palette indices and references as maps.
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self {
+            Color::Preset { name } => ColorRepr::String(name.clone()),
+            Color::Indexed { index } => ColorRepr::Indexed { index: *index },
+            Color::RGB { r, g, b } => ColorRepr::String(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+            Color::Ref { name } => ColorRepr::Ref { name: name.clone() },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    /// Parses a color from `#rrggbb`, `#rgb`, `rgb(r, g, b)` or a
+    /// preset color name.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+
+        if let Some(hex) = s.strip_prefix('#') {
+            return parse_hex_color(hex)
+                .ok_or_else(|| anyhow::anyhow!("Invalid hex color '{}': expected #rrggbb or #rgb", s));
+        }
+
+        let lower = s.to_ascii_lowercase();
+        if let Some(args) = lower.strip_prefix("rgb(") {
+            return parse_rgb_function(args)
+                .ok_or_else(|| anyhow::anyhow!("Invalid color '{}': expected rgb(r, g, b) with values 0-255", s));
+        }
+
+        let normalized = normalize_color_name(s);
+        if PresetColor::from_name(&normalized).is_none() && css::lookup(&normalized).is_none() {
+            anyhow::bail!("Unknown color '{}': expected a color name, #rrggbb or rgb(r, g, b)", s);
+        }
+        Ok(Color::Preset { name: s.to_string() })
+    }
+}
+
+fn parse_hex_color(hex: &str) -> Option<Color> {
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize, len: usize| u8::from_str_radix(&hex[i * len..(i + 1) * len], 16).ok();
+    match hex.len() {
+        6 => Some(Color::RGB { r: channel(0, 2)?, g: channel(1, 2)?, b: channel(2, 2)? }),
+        // #rgb 是 #rrggbb 的简写
+        3 => Some(Color::RGB { r: channel(0, 1)? * 17, g: channel(1, 1)? * 17, b: channel(2, 1)? * 17 }),
+        _ => None,
+    }
+}
+
+fn parse_rgb_function(args: &str) -> Option<Color> {
+    let args = args.strip_suffix(')')?;
+    let mut parts = args.split(',').map(|p| p.trim().parse::<u8>());
+    let (r, g, b) = (parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Color::RGB { r, g, b })
 }
 
 impl Color {
-    /// Converts this color into an ANSI escape sequence.
+    /// Converts this color into SGR parameters, without the surrounding
+    /// `\x1b[` and `m`.
     ///
-    /// The returned string enables the color when written to a terminal.
-    /// Callers are responsible for resetting formatting (e.g. with `\x1b[0m`)
-    /// after use.
-    pub(crate) fn to_ansi(&self) -> anyhow::Result<String> {
+    /// If `background` is `true`, the background variant is produced
+    /// (e.g. `41` instead of `31`). Colors are mapped to the nearest
+    /// color available at `depth`.
+    pub(crate) fn sgr_params(&self, background: bool, depth: ColorDepth) -> anyhow::Result<String> {
+        let kind = if background { 48 } else { 38 };
+
         match self {
             Color::Preset { name } => {
-                let preset = PresetColor::parse(name)?;
-                Ok(preset.to_ansi().parse()?)
+                let normalized = normalize_color_name(name);
+                if let Some(preset) = PresetColor::from_name(&normalized) {
+                    return Ok(ansi16_sgr_code(preset.index(), background).to_string());
+                }
+                let Some((r, g, b)) = css::lookup(&normalized) else {
+                    anyhow::bail!("Unknown preset color: {}", name);
+                };
+                Color::RGB { r, g, b }.sgr_params(background, depth)
             }
-            Color::RGB { r, g, b } => {
-                Ok(format!("\x1b[38;2;{};{};{}m", r, g, b))
+            Color::Indexed { index } => match depth {
+                ColorDepth::Ansi16 => {
+                    let (r, g, b) = ansi256_to_rgb(*index);
+                    let basic = if *index < 16 { *index } else { rgb_to_ansi16(r, g, b) };
+                    Ok(ansi16_sgr_code(basic, background).to_string())
+                }
+                _ => Ok(format!("{};5;{}", kind, index)),
+            },
+            Color::Ref { name } => {
+                anyhow::bail!("Undefined palette color '{}'", name)
+            }
+            Color::RGB { r, g, b } => match depth {
+                ColorDepth::TrueColor | ColorDepth::None => {
+                    Ok(format!("{};2;{};{};{}", kind, r, g, b))
+                }
+                ColorDepth::Ansi256 => {
+                    Ok(format!("{};5;{}", kind, rgb_to_ansi256(*r, *g, *b)))
+                }
+                ColorDepth::Ansi16 => {
+                    let index = rgb_to_ansi16(*r, *g, *b);
+                    Ok(ansi16_sgr_code(index, background).to_string())
+                }
+            },
+        }
+    }
+}
+
+impl Color {
+    /// Resolves palette references, following references between palette
+    /// entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a referenced name is not in `palette` or the
+    /// references form a cycle.
+    pub(crate) fn resolve(&self, palette: &HashMap<String, Color>) -> anyhow::Result<Color> {
+        let mut current = self;
+        let mut seen = Vec::new();
+
+        while let Color::Ref { name } = current {
+            if seen.contains(&name) {
+                anyhow::bail!("Palette color '{}' references itself", name);
+            }
+            seen.push(name);
+            current = palette
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("Undefined palette color '{}'", name))?;
+        }
+        Ok(current.clone())
+    }
+
+    /// Returns the RGB value of this color, for output formats that are
+    /// not limited to terminal colors. The 16 basic colors use the values
+    /// xterm displays them with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for an unknown color name or an unresolved
+    /// palette reference.
+    pub(crate) fn to_rgb(&self) -> anyhow::Result<(u8, u8, u8)> {
+        match self {
+            Color::Preset { name } => {
+                let normalized = normalize_color_name(name);
+                if let Some(preset) = PresetColor::from_name(&normalized) {
+                    return Ok(ANSI16_RGB[preset.index() as usize]);
+                }
+                css::lookup(&normalized).ok_or_else(|| anyhow::anyhow!("Unknown preset color: {}", name))
             }
+            Color::Indexed { index } => Ok(ansi256_to_rgb(*index)),
+            Color::RGB { r, g, b } => Ok((*r, *g, *b)),
+            Color::Ref { name } => anyhow::bail!("Undefined palette color '{}'", name),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Normalizes a color name for lookup: lowercase, with `_`, `-` and
+/// spaces removed.
+fn normalize_color_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// The channel levels of the 6x6x6 color cube in the xterm-256 palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The RGB values xterm uses for the 16 basic colors.
+const ANSI16_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let dr = r1.abs_diff(r2) as u32;
+    let dg = g1.abs_diff(g2) as u32;
+    let db = b1.abs_diff(b2) as u32;
+    dr * dr + dg * dg + db * db
+}
+
+/// Maps an RGB color to the nearest xterm-256 palette index, choosing
+/// between the 6x6x6 color cube and the 24-step grayscale ramp.
+pub(crate) fn rgb_to_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    let nearest_level = |c: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&i| CUBE_LEVELS[i].abs_diff(c))
+            .unwrap()
+    };
+    let (ri, gi, bi) = (nearest_level(r), nearest_level(g), nearest_level(b));
+    let cube_rgb = (CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]);
+    let cube_index = 16 + 36 * ri + 6 * gi + bi;
+
+    // 灰阶 232..=255 对应 8, 18, ..., 238
+    let avg = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_step = (avg.saturating_sub(8) / 10).min(23) as u8;
+    let gray_level = 8 + 10 * gray_step;
+    let gray_rgb = (gray_level, gray_level, gray_level);
+
+    if distance((r, g, b), gray_rgb) < distance((r, g, b), cube_rgb) {
+        232 + gray_step
+    } else {
+        cube_index as u8
+    }
+}
+
+/// Maps an RGB color to the nearest of the 16 basic colors, returned as
+/// an index in `0..16`.
+pub(crate) fn rgb_to_ansi16(r: u8, g: u8, b: u8) -> u8 {
+    (0..ANSI16_RGB.len())
+        .min_by_key(|&i| distance((r, g, b), ANSI16_RGB[i]))
+        .unwrap() as u8
+}
+
+/// Returns the RGB value of an xterm-256 palette index.
+pub(crate) fn ansi256_to_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI16_RGB[index as usize],
+        16..=231 => {
+            let i = (index - 16) as usize;
+            (CUBE_LEVELS[i / 36], CUBE_LEVELS[(i / 6) % 6], CUBE_LEVELS[i % 6])
+        }
+        232..=255 => {
+            let level = 8 + 10 * (index - 232);
+            (level, level, level)
+        }
+    }
+}
+
+/// Returns the SGR code for one of the 16 basic colors.
+fn ansi16_sgr_code(index: u8, background: bool) -> u8 {
+    let base = if index < 8 { 30 + index } else { 90 + index - 8 };
+    if background { base + 10 } else { base }
+}
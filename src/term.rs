@@ -0,0 +1,93 @@
+/// The number of colors the output terminal can display.
+///
+/// Colors that exceed the depth are mapped to the nearest color the
+/// terminal supports: 24-bit RGB values become xterm-256 indices or one
+/// of the 16 basic colors. With [`ColorDepth::None`], no escape sequences
+/// are emitted at all.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ColorDepth {
+    /// 24-bit RGB colors (`38;2;r;g;b`).
+    #[default]
+    TrueColor,
+    /// The xterm 256-color palette (`38;5;n`).
+    Ansi256,
+    /// The 8 standard and 8 bright colors (`30`–`37`, `90`–`97`).
+    Ansi16,
+    /// No colors or attributes.
+    None,
+}
+
+impl ColorDepth {
+    /// Detects the color depth of the current terminal from the
+    /// `COLORTERM` and `TERM` environment variables.
+    pub fn detect() -> Self {
+        let colorterm = std::env::var("COLORTERM").ok();
+        let term = std::env::var("TERM").ok();
+        Self::from_env(colorterm.as_deref(), term.as_deref())
+    }
+
+    /// Determines the color depth from the values of `COLORTERM` and `TERM`.
+    ///
+    /// - `COLORTERM=truecolor` or `COLORTERM=24bit` selects true color
+    /// - a `TERM` mentioning `truecolor`, `24bit` or `direct` selects true color
+    /// - a `TERM` mentioning `256` selects the 256-color palette
+    /// - `TERM=dumb` disables colors
+    /// - anything else falls back to the 16 basic colors
+    pub(crate) fn from_env(colorterm: Option<&str>, term: Option<&str>) -> Self {
+        if let Some(colorterm) = colorterm {
+            let colorterm = colorterm.to_ascii_lowercase();
+            if colorterm == "truecolor" || colorterm == "24bit" {
+                return ColorDepth::TrueColor;
+            }
+        }
+
+        let Some(term) = term else {
+            return ColorDepth::Ansi16;
+        };
+        let term = term.to_ascii_lowercase();
+
+        if term == "dumb" {
+            ColorDepth::None
+        } else if term.contains("truecolor") || term.contains("24bit") || term.contains("direct") {
+            ColorDepth::TrueColor
+        } else if term.contains("256") {
+            ColorDepth::Ansi256
+        } else {
+            ColorDepth::Ansi16
+        }
+    }
+}
+
+/// Decides whether colored output should be produced when the user asked
+/// for automatic detection.
+///
+/// `CLICOLOR_FORCE` (set to anything but `0`) forces colors on, a
+/// non-empty `NO_COLOR` turns them off, and otherwise colors are enabled
+/// only when stdout is a terminal.
+pub fn colors_enabled() -> bool {
+    use std::io::IsTerminal;
+
+    let clicolor_force = std::env::var("CLICOLOR_FORCE").ok();
+    let no_color = std::env::var("NO_COLOR").ok();
+    colors_enabled_from_env(
+        clicolor_force.as_deref(),
+        no_color.as_deref(),
+        std::io::stdout().is_terminal(),
+    )
+}
+
+/// Determines whether colors are enabled from the values of
+/// `CLICOLOR_FORCE` and `NO_COLOR` and whether stdout is a terminal.
+pub(crate) fn colors_enabled_from_env(
+    clicolor_force: Option<&str>,
+    no_color: Option<&str>,
+    stdout_is_terminal: bool,
+) -> bool {
+    if clicolor_force.is_some_and(|v| !v.is_empty() && v != "0") {
+        return true;
+    }
+    if no_color.is_some_and(|v| !v.is_empty()) {
+        return false;
+    }
+    stdout_is_terminal
+}
@@ -0,0 +1,99 @@
+use crate::rules::{Color, Style};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Built-in themes, as `(name, YAML source)` pairs.
+const BUILTIN_THEMES: &[(&str, &str)] = &[
+    ("dark", include_str!("theme/dark.yaml")),
+    ("light", include_str!("theme/light.yaml")),
+];
+
+/// The theme used when none is selected.
+pub const DEFAULT_THEME: &str = "dark";
+
+/// A mapping from semantic kinds to styles.
+///
+/// Rules name a `kind` (e.g. `string`, `comment`, `level.error`) instead of
+/// hardcoding colors, and the theme decides how each kind looks. Kinds are
+/// hierarchical: if a theme has no style for `level.error`, the style for
+/// `level` is used.
+///
+/// # YAML
+///
+/// ```yaml
+/// palette:
+///   red: "#f44747"
+///
+/// styles:
+///   comment: { foreground: "#6a9955", attributes: [italic] }
+///   level: { foreground: { name: Cyan } }
+///   level.error: { foreground: { ref: red }, attributes: [bold] }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Theme {
+    /// Named colors that styles can reference with `{ ref: <name> }`.
+    #[serde(default)]
+    pub palette: HashMap<String, Color>,
+
+    /// Styles by kind.
+    #[serde(default)]
+    pub styles: HashMap<String, Style>,
+}
+
+impl Theme {
+    /// Loads a built-in theme by name, or a theme file if `name` is not a
+    /// built-in theme.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the theme file cannot be read or parsed, or if
+    /// a style references an undefined palette color.
+    pub fn load(name: &str) -> anyhow::Result<Self> {
+        let lower = name.to_ascii_lowercase();
+        if let Some((_, source)) = BUILTIN_THEMES.iter().find(|(n, _)| *n == lower) {
+            return Self::from_yaml(source);
+        }
+
+        let path = Path::new(name);
+        if !path.is_file() {
+            anyhow::bail!(
+                "Unknown theme '{}': not a built-in theme ({}) or a file",
+                name,
+                builtin_names().join(", ")
+            );
+        }
+        Self::from_yaml(&fs::read_to_string(path)?)
+    }
+
+    /// Parses a theme from YAML and resolves its palette references.
+    fn from_yaml(text: &str) -> anyhow::Result<Self> {
+        let mut theme: Theme = serde_yml::from_str(text)?;
+        for (kind, style) in theme.styles.iter_mut() {
+            for color in [&mut style.foreground, &mut style.background].into_iter().flatten() {
+                *color = color
+                    .resolve(&theme.palette)
+                    .map_err(|e| anyhow::anyhow!("{} in theme style '{}'", e, kind))?;
+            }
+        }
+        Ok(theme)
+    }
+
+    /// Returns the style for `kind`, falling back to its parent kinds
+    /// (`level.error` → `level`).
+    pub fn style_for(&self, kind: &str) -> Option<&Style> {
+        let mut kind = kind;
+        loop {
+            if let Some(style) = self.styles.get(kind) {
+                return Some(style);
+            }
+            kind = &kind[..kind.rfind('.')?];
+        }
+    }
+}
+
+/// Returns the names of all built-in themes.
+pub fn builtin_names() -> Vec<&'static str> {
+    BUILTIN_THEMES.iter().map(|(name, _)| *name).collect()
+}